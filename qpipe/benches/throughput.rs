@@ -0,0 +1,177 @@
+// `cargo bench -p qpipe --bench throughput`
+//
+// Pushes bytes through a loopback QUIC stream, framing on one side and unframing on the other,
+// first with the original 256-byte framing loop, then with `copy_framing`/`copy_unframing`
+// over v2, and prints the throughput of each.
+
+use std::time::Instant;
+
+use anyhow::Result;
+use qpipe::extension::{Extensions, Seen};
+use qpipe::frame::{copy_framing, copy_unframing, HeaderHeader, Version};
+use qpipe::shaping::Shaping;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const TOTAL: u64 = 64 * 1024 * 1024;
+
+fn main() -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let before = measure(Path::Before).await?;
+            let after = measure(Path::After).await?;
+            println!("before: {before:>8.1} MiB/s");
+            println!("after:  {after:>8.1} MiB/s");
+            println!("speedup: {:.1}x", after / before);
+            Ok(())
+        })
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Path {
+    Before,
+    After,
+}
+
+async fn measure(path: Path) -> Result<f64> {
+    let (server, client) = endpoints()?;
+    let addr = server.local_addr()?;
+
+    let receiver = tokio::spawn(async move {
+        let conn = server.accept().await.expect("endpoint open").await?;
+        let (_, mut framed_from) = conn.accept_bi().await?;
+        let mut sink = Counter(0);
+        let extensions = Extensions::default();
+        match path {
+            Path::Before => legacy_copy_unframing(&mut framed_from, &mut sink).await?,
+            Path::After => {
+                copy_unframing(
+                    &mut framed_from,
+                    &mut sink,
+                    Version::V2,
+                    extensions.dispatch(Seen::Package),
+                    &Shaping::default(),
+                )
+                .await?
+            }
+        }
+        anyhow::ensure!(sink.0 == TOTAL, "lost data: {}", sink.0);
+        Ok::<_, anyhow::Error>(())
+    });
+
+    let conn = client.connect(addr, "localhost")?.await?;
+    let (mut framed_to, _) = conn.open_bi().await?;
+    let plain = tokio::io::repeat(0x55).take(TOTAL);
+
+    let start = Instant::now();
+    match path {
+        Path::Before => legacy_copy_framing(plain, &mut framed_to).await?,
+        Path::After => {
+            copy_framing(plain, &mut framed_to, Version::V2, &Shaping::default()).await?
+        }
+    }
+    let version = match path {
+        Path::Before => Version::V1,
+        Path::After => Version::V2,
+    };
+    HeaderHeader::finished()
+        .write_all(&mut framed_to, version)
+        .await?;
+    framed_to.finish().await?;
+    receiver.await??;
+    let elapsed = start.elapsed();
+
+    conn.close(0u32.into(), b"done");
+    client.wait_idle().await;
+
+    Ok(TOTAL as f64 / 1024. / 1024. / elapsed.as_secs_f64())
+}
+
+fn endpoints() -> Result<(quinn::Endpoint, quinn::Endpoint)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let cert_der = rustls::Certificate(cert.serialize_der()?);
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+    let server_config = quinn::ServerConfig::with_single_cert(vec![cert_der.clone()], key)?;
+    let server = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse()?)?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&cert_der)?;
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse()?)?;
+    client.set_default_client_config(quinn::ClientConfig::with_root_certificates(roots));
+
+    Ok((server, client))
+}
+
+struct Counter(u64);
+
+impl AsyncWrite for Counter {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.0 += buf.len() as u64;
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+// the data path as it was: a 256 byte read buffer, and one header + data write per read
+async fn legacy_copy_framing(
+    mut from_plain: impl AsyncRead + Unpin,
+    mut to_framed: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    let mut buf = [0u8; 256];
+    loop {
+        let found = from_plain.read(&mut buf).await?;
+        let buf = &buf[..found];
+        if buf.is_empty() {
+            break;
+        }
+        HeaderHeader::data(buf.len())
+            .write_all(&mut to_framed, Version::V1)
+            .await?;
+        to_framed.write_all(buf).await?;
+    }
+    Ok(())
+}
+
+// ..and an 8096 byte buffer on the receiving side
+async fn legacy_copy_unframing(
+    mut from_framed: impl AsyncRead + Unpin,
+    mut to_plain: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    let mut buf = [0u8; 8096];
+    loop {
+        let hh = HeaderHeader::from(&mut from_framed, Version::V1).await?;
+        match &hh.four_cc {
+            b"data" => (),
+            b"fini" => break,
+            _ => anyhow::bail!("unsupported frame on established connection: {:?}", hh),
+        };
+        anyhow::ensure!(
+            hh.data_len <= buf.len() as u64,
+            "overlong data packet: {}",
+            hh.data_len
+        );
+        let buf = &mut buf[..hh.data_len as usize];
+        from_framed.read_exact(buf).await?;
+        to_plain.write_all(buf).await?;
+    }
+    Ok(())
+}
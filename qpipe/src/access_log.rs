@@ -0,0 +1,265 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Error, Result};
+use log::error;
+use serde::Serialize;
+
+use crate::frame::reset_code;
+
+/// one JSON object per line, per stream, written as each stream finishes; by a thread of its
+/// own, so a slow disk holds up nothing but the log
+pub struct AccessLog {
+    lines: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Record {
+    /// fingerprint of the client's certificate
+    pub identity: String,
+    pub peer: String,
+    /// as requested by the client
+    pub address_port: String,
+    /// what we actually connected to, if we got that far
+    pub resolved: Option<String>,
+    pub start_ms: u64,
+    pub duration_ms: u64,
+    /// bytes from the target, sent down the tunnel
+    pub bytes_sent: u64,
+    /// bytes from the tunnel, written to the target
+    pub bytes_received: u64,
+    pub close: Close,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Close {
+    /// both sides finished cleanly
+    Fini,
+    /// either side reset the stream
+    Reset,
+    /// we declined to connect, with an `errm`
+    Refused,
+    Error,
+}
+
+impl AccessLog {
+    /// append to `path`, which only we can read if it's new, or write to stdout if it's `-`
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut out: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(LineWriter::new(io::stdout()))
+        } else {
+            let mut options = OpenOptions::new();
+            options.create(true).append(true);
+            // who connected to what is nobody else's business
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let file: File = options
+                .open(path)
+                .with_context(|| anyhow!("opening access log {path:?}"))?;
+            Box::new(LineWriter::new(file))
+        };
+        let (lines, queued) = mpsc::channel::<Vec<u8>>();
+        let writer = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in queued {
+                    if let Err(e) = out.write_all(&line) {
+                        error!("writing access log: {e:?}");
+                    }
+                }
+            })
+            .context("starting access log writer")?;
+        Ok(AccessLog {
+            lines: Some(lines),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn record(&self, record: &Record) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("serialising access log record {record:?}: {e:?}");
+                return;
+            }
+        };
+        line.push(b'\n');
+        let sent = self.lines.as_ref().map(|lines| lines.send(line));
+        if let Some(Err(e)) = sent {
+            error!(
+                "access log writer has stopped, dropping {}",
+                String::from_utf8_lossy(&e.0)
+            );
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    /// wait for everything recorded to be written
+    fn drop(&mut self) {
+        drop(self.lines.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Record {
+    pub fn new(identity: String, peer: String, address_port: String, started: SystemTime) -> Self {
+        Record {
+            identity,
+            peer,
+            address_port,
+            resolved: None,
+            start_ms: started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            duration_ms: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            close: Close::Error,
+            code: None,
+            error: None,
+        }
+    }
+
+    /// fill in the close reason and duration from how the stream ended
+    pub fn finish(&mut self, started: SystemTime, res: &Result<()>) {
+        self.duration_ms = started.elapsed().unwrap_or_default().as_millis() as u64;
+        let (close, code) = match res {
+            Ok(()) => (Close::Fini, None),
+            Err(e) => match reset_code(e) {
+                Some(code) => (Close::Reset, code),
+                None => (Close::Error, None),
+            },
+        };
+        self.close = close;
+        self.code = code;
+        self.error = res.as_ref().err().map(|e| format!("{e:#}"));
+    }
+
+    pub fn refused(&mut self, started: SystemTime, code: u32, e: &Error) {
+        self.duration_ms = started.elapsed().unwrap_or_default().as_millis() as u64;
+        self.close = Close::Refused;
+        self.code = Some(code);
+        self.error = Some(format!("{e:#}"));
+    }
+}
+
+/// the `n`th record in `path`, once it's been written
+#[cfg(test)]
+async fn nth_record(path: &Path, n: usize) -> Result<serde_json::Value> {
+    let line = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            if let Some(line) = std::fs::read_to_string(path)?.lines().nth(n) {
+                return Ok::<_, Error>(line.to_string());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    })
+    .await??;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[tokio::test]
+async fn test_access_log() -> Result<()> {
+    use crate::client::{echo_server, establish_to, TestServer};
+    use crate::extension::{Extensions, Seen};
+    use crate::frame::{splice, Version, ERR_CONNECT, FLAG_RAW, RESET_TCP};
+    use crate::shaping::Shaping;
+    use crate::wire;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("access.log");
+    let server = TestServer::start_with(crate::server::Options {
+        access_log: Some(path.clone()),
+        ..Default::default()
+    })?;
+    let echo = echo_server().await?;
+    let nothing = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let conn = server.connect().await?;
+    let version = Version::negotiated(&conn);
+    let extensions = Extensions::default();
+
+    // finished by both sides
+    let (mut framed_to, mut framed_from) = conn.open_bi().await?;
+    wire::write_establish(&mut framed_to, version, &establish_to(echo)).await?;
+    let flags = wire::read_okay(
+        &mut framed_from,
+        version,
+        extensions.dispatch(Seen::Package),
+    )
+    .await?;
+    let mut received = Vec::new();
+    splice(
+        version,
+        flags & FLAG_RAW != 0,
+        extensions.dispatch(Seen::Package),
+        &Shaping::default(),
+        b"hello".as_slice(),
+        &mut received,
+        &mut framed_to,
+        &mut framed_from,
+    )
+    .await?;
+    assert_eq!(b"hello", received.as_slice());
+    let record = nth_record(&path, 0).await?;
+    assert_eq!("fini", record["close"]);
+    assert_eq!(None, record.get("code"));
+    assert_eq!(echo.to_string(), record["address_port"]);
+    assert_eq!(echo.to_string(), record["resolved"]);
+    assert_eq!(5, record["bytes_sent"]);
+    assert_eq!(5, record["bytes_received"]);
+    assert!(!record["identity"].as_str().unwrap_or_default().is_empty());
+
+    // reset by us
+    let (mut framed_to, mut framed_from) = conn.open_bi().await?;
+    wire::write_establish(&mut framed_to, version, &establish_to(echo)).await?;
+    wire::read_okay(
+        &mut framed_from,
+        version,
+        extensions.dispatch(Seen::Package),
+    )
+    .await?;
+    framed_to.reset(RESET_TCP.into())?;
+    let record = nth_record(&path, 1).await?;
+    assert_eq!("reset", record["close"]);
+    assert_eq!(RESET_TCP, record["code"]);
+
+    // nothing listening there
+    let (mut framed_to, mut framed_from) = conn.open_bi().await?;
+    wire::write_establish(&mut framed_to, version, &establish_to(nothing)).await?;
+    let refused = wire::read_okay(
+        &mut framed_from,
+        version,
+        extensions.dispatch(Seen::Package),
+    );
+    assert!(refused.await.is_err());
+    let record = nth_record(&path, 2).await?;
+    assert_eq!("refused", record["close"]);
+    assert_eq!(ERR_CONNECT, record["code"]);
+    assert_eq!(serde_json::Value::Null, record["resolved"]);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+    }
+
+    conn.close(crate::frame::CLOSE_DONE.into(), b"test finished");
+    server.stop().await
+}
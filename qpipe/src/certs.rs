@@ -1,49 +1,168 @@
-use std::fs::Permissions;
 use std::path::Path;
 use std::{fs, io};
 
-use anyhow::{anyhow, bail, ensure, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
+use log::info;
 use rcgen::{
     BasicConstraints, Certificate, CertificateParams, CertificateSigningRequest, DistinguishedName,
     DnType, ExtendedKeyUsagePurpose, IsCa, KeyUsagePurpose,
 };
 use rustls::PrivateKey;
 
+use crate::seal::{self, Unlock};
+use crate::state;
+
 type KeyPair = (rustls::Certificate, rustls::PrivateKey);
 #[derive(Clone, Debug)]
-pub struct Csr(Vec<u8>);
+pub struct Csr(pub Vec<u8>);
+
+/// how private keys in the state dir are read, and written
+#[derive(Debug, Default)]
+pub struct KeyStorage {
+    /// for keys which are encrypted, and for encrypting
+    pub unlock: Unlock,
+    /// encrypt new keys, and any existing ones which aren't already
+    pub encrypt: bool,
+}
+
+impl KeyStorage {
+    fn to_disk(&self, key: &PrivateKey, what: &str) -> Result<Vec<u8>> {
+        if !self.encrypt {
+            return Ok(key.0.clone());
+        }
+        seal::seal(&key.0, &self.unlock.passphrase(what)?)
+    }
+}
 
 // note that, despite the return types, there's not a single iota
 // of validation of the returned objects in this method
 fn load_or_generate(
     root: impl AsRef<Path>,
     short_name: &str,
+    storage: &KeyStorage,
     generate: impl FnOnce() -> Result<KeyPair>,
 ) -> Result<KeyPair> {
     let path = root.as_ref();
     let cert_path = path.join(format!("{short_name}.cert"));
     let key_path = path.join(format!("{short_name}.key"));
+    let what = format!("the key in {key_path:?}");
+    state::ensure_private_dir(path)?;
 
     match fs::read(&cert_path) {
-        Ok(cert) => Ok((
-            rustls::Certificate(cert),
-            rustls::PrivateKey(
-                fs::read(key_path).context("loading key after already loading cert")?,
-            ),
-        )),
+        Ok(cert) => {
+            state::check_private(&key_path)?;
+            let stored = fs::read(&key_path).context("loading key after already loading cert")?;
+            let key = rustls::PrivateKey(storage.unlock.open(&stored, &what)?);
+            if storage.encrypt && !seal::is_sealed(&stored) {
+                info!("encrypting {key_path:?}");
+                state::write_private(&key_path, &storage.to_disk(&key, &what)?)
+                    .context("failed to write private key")?;
+            }
+            Ok((rustls::Certificate(cert), key))
+        }
         Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
             let (cert, key) = generate()?;
-            fs::write(&cert_path, &cert.0)
+            let stored = storage.to_disk(&key, &what)?;
+            // the key first, as the cert existing means the key does
+            state::write_private(&key_path, &stored).context("failed to write private key")?;
+            state::write_private(&cert_path, &cert.0)
                 .with_context(|| anyhow!("failed to write certificate to {:?}", cert_path))?;
-            fs::write(&key_path, &key.0).context("failed to write private key")?;
             Ok((cert, key))
         }
         Err(e) => Err(e).with_context(|| anyhow!("failed to read cert from {cert_path:?}")),
     }
 }
 
-pub fn server(state_dir: impl AsRef<Path>, names: &[&str]) -> Result<KeyPair> {
-    load_or_generate(state_dir, "server", || generate_server_certs(names))
+/// The CA which issues both our server certificate and the client certificates; it's what
+/// clients trust. It used to be the server certificate itself, so packages issued before
+/// there was a separate one still work.
+pub fn ca(state_dir: impl AsRef<Path>, storage: &KeyStorage) -> Result<KeyPair> {
+    rename_ca(state_dir.as_ref())?;
+    load_or_generate(state_dir, "ca", storage, generate_ca)
+}
+
+/// state dirs used to keep the CA as "server", and the server certificate as "leaf"
+fn rename_ca(state_dir: &Path) -> Result<()> {
+    if state_dir.join("ca.cert").exists() || !state_dir.join("server.cert").exists() {
+        return Ok(());
+    }
+    info!("renaming the CA in {state_dir:?} from server.cert to ca.cert");
+    for (from, to) in [("server", "ca"), ("leaf", "server")] {
+        // the key first, as the cert existing means the key does
+        for extension in ["key", "cert"] {
+            let from = state_dir.join(format!("{from}.{extension}"));
+            if from.exists() {
+                let to = state_dir.join(format!("{to}.{extension}"));
+                fs::rename(&from, &to).with_context(|| anyhow!("renaming {from:?}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// our chain, leaf first, then the CA, and the leaf's key
+pub fn server(
+    state_dir: impl AsRef<Path>,
+    names: &[&str],
+    storage: &KeyStorage,
+) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+    let state_dir = state_dir.as_ref();
+    let (ca_cert, ca_key) = ca(state_dir, storage)?;
+    let (leaf_cert, leaf_key) = load_or_generate(state_dir, "server", storage, || {
+        generate_server_leaf(names, &issuer(&ca_cert, &ca_key)?)
+    })?;
+    Ok((vec![leaf_cert, ca_cert], leaf_key))
+}
+
+/// a short, stable name for whoever holds this certificate
+pub fn fingerprint(cert: &rustls::Certificate) -> String {
+    ring::digest::digest(&ring::digest::SHA256, &cert.0).as_ref()[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// the whole SHA-256 of a certificate, for pinning
+pub fn pin(cert: &rustls::Certificate) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, &cert.0)
+        .as_ref()
+        .to_vec()
+}
+
+/// the fingerprint of the certificate the peer presented during the handshake
+pub fn peer_fingerprint(conn: &quinn::Connection) -> String {
+    conn.peer_identity()
+        .and_then(|certs| certs.downcast::<Vec<rustls::Certificate>>().ok())
+        .and_then(|certs| certs.first().map(fingerprint))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// a new key for a client which keeps its own, written as "client.key" in the state dir,
+/// and the CSR to `issue` its package with, as "client.csr"
+pub fn client_request(state_dir: impl AsRef<Path>, storage: &KeyStorage) -> Result<Csr> {
+    let state_dir = state_dir.as_ref();
+    state::ensure_private_dir(state_dir)?;
+    let key_path = state_dir.join("client.key");
+    ensure!(
+        !key_path.exists(),
+        "{key_path:?} already exists, and packages may have been issued for it"
+    );
+    let (csr, key) = generate_client_certs()?;
+    let stored = storage.to_disk(&key, &format!("the key in {key_path:?}"))?;
+    state::write_private(&key_path, &stored).context("failed to write private key")?;
+    let csr_path = state_dir.join("client.csr");
+    state::write_private(&csr_path, &csr.0)
+        .with_context(|| anyhow!("failed to write CSR to {csr_path:?}"))?;
+    Ok(csr)
+}
+
+/// a key from `client_request`, for a package issued for its CSR
+pub fn client_key(path: &Path, unlock: &Unlock) -> Result<PrivateKey> {
+    state::check_private(path)?;
+    let stored = fs::read(path).with_context(|| anyhow!("reading key from {path:?}"))?;
+    Ok(PrivateKey(
+        unlock.open(&stored, &format!("the key in {path:?}"))?,
+    ))
 }
 
 pub fn parse_client(buf: &[u8]) -> Result<CertificateSigningRequest> {
@@ -51,23 +170,97 @@ pub fn parse_client(buf: &[u8]) -> Result<CertificateSigningRequest> {
 }
 
 pub fn mint_client(
+    ca_cert: &rustls::Certificate,
     ca_key: &rustls::PrivateKey,
     client_csr: &CertificateSigningRequest,
 ) -> Result<rustls::Certificate> {
     // ensure!(client_csr.params.is_ca == IsCa::ExplicitNoCa, "{:?} should be ExplicitNoCa", client_csr.params.is_ca);
-    let mut ca_builder = rcgen::CertificateParams::new([]);
-    ca_builder.key_pair = Some(rcgen::KeyPair::from_der(&ca_key.0)?);
-    let ca = rcgen::Certificate::from_params(ca_builder)?;
-    let cert = client_csr.serialize_der_with_signer(&ca)?;
+    let cert = client_csr.serialize_der_with_signer(&issuer(ca_cert, ca_key)?)?;
     Ok(rustls::Certificate(cert))
 }
 
+/// the CA in a form rcgen can sign with; its name has to match what's in the certificate,
+/// or nothing will chain to it
+fn issuer(ca_cert: &rustls::Certificate, ca_key: &rustls::PrivateKey) -> Result<Certificate> {
+    let params =
+        CertificateParams::from_ca_cert_der(&ca_cert.0, rcgen::KeyPair::from_der(&ca_key.0)?)?;
+    Ok(Certificate::from_params(params)?)
+}
+
 #[test]
 fn test_gen_client() -> Result<()> {
     let state_dir = tempfile::tempdir()?;
-    let (_ca_cert, ca_key) = server(state_dir, &["localhost"])?;
-    let (csr, client_keys) = generate_client_certs()?;
-    let client_cert = mint_client(&ca_key, &parse_client(&csr.0)?)?;
+    let storage = KeyStorage::default();
+    let (ca_cert, ca_key) = ca(&state_dir, &storage)?;
+    let (chain, _server_key) = server(&state_dir, &["localhost"], &storage)?;
+    assert_eq!(ca_cert, chain[1]);
+    let (csr, _client_keys) = generate_client_certs()?;
+    let _client_cert = mint_client(&ca_cert, &ca_key, &parse_client(&csr.0)?)?;
+    Ok(())
+}
+
+#[test]
+fn test_client_request() -> Result<()> {
+    let state_dir = tempfile::tempdir()?;
+    let storage = KeyStorage {
+        unlock: Unlock::fixed("hunter2"),
+        encrypt: true,
+    };
+    let csr = client_request(&state_dir, &storage)?;
+    assert_eq!(csr.0, fs::read(state_dir.path().join("client.csr"))?);
+    let (ca_cert, ca_key) = ca(&state_dir, &storage)?;
+    let _client_cert = mint_client(&ca_cert, &ca_key, &parse_client(&csr.0)?)?;
+
+    let key_path = state_dir.path().join("client.key");
+    assert!(seal::is_sealed(&fs::read(&key_path)?));
+    let key = client_key(&key_path, &storage.unlock)?;
+    rcgen::KeyPair::from_der(&key.0)?;
+
+    // replacing the key would strand packages issued for it
+    assert!(client_request(&state_dir, &storage).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_rename_ca() -> Result<()> {
+    let state_dir = tempfile::tempdir()?;
+    let storage = KeyStorage::default();
+    let (chain, _server_key) = server(&state_dir, &["localhost"], &storage)?;
+    let dir = state_dir.path();
+    for (from, to) in [("server", "leaf"), ("ca", "server")] {
+        for extension in ["key", "cert"] {
+            fs::rename(
+                dir.join(format!("{from}.{extension}")),
+                dir.join(format!("{to}.{extension}")),
+            )?;
+        }
+    }
+
+    // the old layout is moved, not replaced
+    assert_eq!(chain, server(&state_dir, &["localhost"], &storage)?.0);
+    assert!(dir.join("ca.cert").exists() && !dir.join("leaf.cert").exists());
+    Ok(())
+}
+
+#[test]
+fn test_encrypted_keys() -> Result<()> {
+    let state_dir = tempfile::tempdir()?;
+    let (_cert, plain_key) = ca(&state_dir, &KeyStorage::default())?;
+
+    // existing plain keys get encrypted
+    let encrypting = KeyStorage {
+        unlock: Unlock::fixed("hunter2"),
+        encrypt: true,
+    };
+    assert_eq!(plain_key, ca(&state_dir, &encrypting)?.1);
+    assert!(seal::is_sealed(&fs::read(state_dir.path().join("ca.key"))?));
+
+    assert!(ca(&state_dir, &KeyStorage::default()).is_err());
+    let unlocking = KeyStorage {
+        unlock: Unlock::fixed("hunter2"),
+        encrypt: false,
+    };
+    assert_eq!(plain_key, ca(&state_dir, &unlocking)?.1);
     Ok(())
 }
 
@@ -81,27 +274,37 @@ pub fn generate_client_certs() -> Result<(Csr, rustls::PrivateKey)> {
     ))
 }
 
-fn generate_server_certs(names: &[&str]) -> Result<KeyPair> {
-    let mut params =
-        rcgen::CertificateParams::new(names.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+fn generate_ca() -> Result<KeyPair> {
+    let mut params = CertificateParams::default();
     params.distinguished_name = DistinguishedName::new();
     params
         .distinguished_name
         .push(DnType::CommonName, "qpiped server");
+    // only ever issues leaves
     params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
     use KeyUsagePurpose::*;
     params.key_usages = vec![KeyCertSign, CrlSign, DigitalSignature];
-    use ExtendedKeyUsagePurpose::*;
-    params.extended_key_usages = vec![ServerAuth];
     let cert = Certificate::from_params(params)?;
     let key = cert.serialize_private_key_der();
     let cert = cert.serialize_der()?;
     Ok((rustls::Certificate(cert), PrivateKey(key)))
 }
 
-fn load_or_generate_key(state_dir: impl AsRef<Path>) -> Result<KeyPair> {
-    let key = rcgen::KeyPair::generate(&CertificateParams::default().alg)?;
-    unimplemented!()
+fn generate_server_leaf(names: &[&str], ca: &Certificate) -> Result<KeyPair> {
+    let mut params =
+        rcgen::CertificateParams::new(names.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "qpiped server");
+    use KeyUsagePurpose::*;
+    params.key_usages = vec![DigitalSignature];
+    use ExtendedKeyUsagePurpose::*;
+    params.extended_key_usages = vec![ServerAuth];
+    let cert = Certificate::from_params(params)?;
+    let key = cert.serialize_private_key_der();
+    let cert = cert.serialize_der_with_signer(ca)?;
+    Ok((rustls::Certificate(cert), PrivateKey(key)))
 }
 
 // client generates a key in a file; it's their key everywhere
@@ -110,15 +313,3 @@ fn load_or_generate_key(state_dir: impl AsRef<Path>) -> Result<KeyPair> {
 // store this .. certificate pair under, say, the server's cert's fingerprint
 // client picks this during negotiation, sigh
 // much better to just not store it; in memory only? If so, why bother saving the key.
-
-#[cfg(unix)]
-fn remove_access(path: &Path) -> Result<()> {
-    use std::os::unix::fs::PermissionsExt;
-    Ok(fs::set_permissions(path, Permissions::from_mode(0o700))?)
-}
-
-#[cfg(windows)]
-fn remove_access(path: &Path) -> Result<()> {
-    // windows is secure by default
-    Ok(())
-}
@@ -1,105 +1,1982 @@
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Arc;
-
-use anyhow::{bail, Context, Error, Result};
-use futures_util::future::try_join_all;
-use log::{error, warn};
-use quinn::Connection;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::try_join;
-
-use super::frame::{copy_framing, copy_unframing};
-use super::package::ClientCerts;
-use super::server::alpn_protocols;
-use super::wire;
-use crate::frame::HeaderHeader;
-use crate::wire::Establish;
-
-pub async fn run(target: String, certs: &ClientCerts, mappings: &[(String, String)]) -> Result<()> {
-    let targets: Vec<SocketAddr> = target.to_socket_addrs()?.collect();
-    if targets.is_empty() {
-        bail!("{:?} resolved to nowhere", target);
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use futures_util::future::{self, BoxFuture, FutureExt as _, Shared};
+use log::{debug, error, info, warn};
+use quinn::{Connection, Endpoint, ZeroRttAccepted};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{lookup_host, TcpListener};
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+use super::extension::{Extensions, Seen};
+use super::frame::{
+    alpn_protocols, describe_versions, no_shared_version, reset_tcp, splice, wants_tcp_reset,
+    CLOSE_DONE, FLAG_RAW, SUPPORTED_FLAGS,
+};
+use super::metrics::{self, Metrics};
+use super::package::{ClientCerts, DEFAULT_SERVER_NAME};
+use super::shaping::{Shape, Shaper, Shaping};
+use super::transport::Transport;
+use super::{certs, wire};
+use crate::frame::{HeaderHeader, Version};
+use crate::streams::{Counting, Streams};
+use crate::wire::{Establish, Refused};
+
+/// how long to wait before first retrying a lost connection in a pool; doubled each failure
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct Options {
+    /// listen for `fwd` and `status` requests on this unix socket
+    pub control: Option<PathBuf>,
+    /// how long to wait for established streams to finish, after being asked to shut down
+    pub drain_timeout: Duration,
+    /// serve prometheus metrics over http on this address
+    pub metrics: Option<SocketAddr>,
+    /// handlers for 'xt??' frames sent by the server
+    pub extensions: Extensions,
+    /// expected in the server's certificate, and sent as the SNI
+    pub server_name: String,
+    /// the SHA-256 of the server's certificate, if it must match exactly
+    pub server_pin: Option<Vec<u8>>,
+    pub transport: Transport,
+    /// already bound, e.g. by systemd, with the source they stand for, e.g. `systemd:ssh`,
+    /// and the target each forwards to
+    pub listeners: Vec<(String, std::net::TcpListener, String)>,
+    /// bytes per second, across every stream
+    pub bandwidth: Option<u64>,
+    /// for the streams of these forwards, by source, as given in the mappings
+    pub shapes: HashMap<String, Shape>,
+    /// parallel connections to the server, each with its own congestion control, and
+    /// replaced independently if lost; with just one, losing it ends `run`
+    pub connections: usize,
+    /// which of the `connections` each new stream goes over
+    pub scheduler: Scheduler,
+    /// move to a fresh socket when the network changes, e.g. a laptop changing wifi, so
+    /// the connections follow; only noticed on linux
+    pub migrate: bool,
+    /// rather than connecting at start, connect when a forward accepts its first stream,
+    /// and hang up after this long without any, until the next; losing the connection
+    /// then doesn't end `run` either
+    pub on_demand: Option<Duration>,
+    /// tell systemd when we're ready, stopping, and still alive
+    #[cfg(unix)]
+    pub notify: crate::systemd::Notify,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            control: None,
+            drain_timeout: Duration::from_secs(30),
+            metrics: None,
+            extensions: Extensions::default(),
+            server_name: DEFAULT_SERVER_NAME.to_string(),
+            server_pin: None,
+            transport: Transport::default(),
+            listeners: Vec::new(),
+            bandwidth: None,
+            shapes: HashMap::new(),
+            connections: 1,
+            scheduler: Scheduler::default(),
+            migrate: true,
+            on_demand: None,
+            #[cfg(unix)]
+            notify: Default::default(),
+        }
+    }
+}
+
+/// how a new stream picks which of the pool's connections to go over
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Scheduler {
+    /// each in turn
+    #[default]
+    RoundRobin,
+    /// whichever has the fewest of our streams open
+    LeastStreams,
+    /// by target, so all the streams to a target share a connection
+    TargetHash,
+}
+
+impl FromStr for Scheduler {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "round-robin" => Scheduler::RoundRobin,
+            "least-streams" => Scheduler::LeastStreams,
+            "target-hash" => Scheduler::TargetHash,
+            _ => bail!("unknown scheduler {s:?}: try round-robin, least-streams or target-hash"),
+        })
+    }
+}
+
+/// forward until the connection is lost, or `shutdown` completes (then drain)
+pub async fn run(
+    target: String,
+    certs: &ClientCerts,
+    mappings: &[(String, String)],
+    options: &Options,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    ensure!(options.connections > 0, "at least one connection is needed");
+    let config = client_config(certs, &options.transport)?;
+    let endpoints = (0..options.connections)
+        .map(|_| endpoint(&config))
+        .collect::<Result<Vec<_>>>()?;
+    let metrics = Arc::new(Metrics::default());
+    let pool = match options.on_demand {
+        None => future::try_join_all(
+            endpoints
+                .iter()
+                .map(|endpoint| connect(endpoint, &target, options, &metrics)),
+        )
+        .await?
+        .into_iter()
+        .map(|(conn, confirmed)| Some(Slot::new(conn, confirmed)))
+        .collect(),
+        Some(_) => vec![None; options.connections],
+    };
+
+    let client = Client::new(target, endpoints, pool, options, metrics);
+    // on demand, each connection's supervisor is started with it, and just tidies up after
+    let mut supervisors = match options.on_demand {
+        None => (0..client.pool.len())
+            .map(|index| tokio::spawn(Arc::clone(&client).supervise(index)))
+            .collect(),
+        Some(_) => Vec::new(),
+    };
+    let idler = options
+        .on_demand
+        .map(|idle| tokio::spawn(Arc::clone(&client).hang_up_when_idle(idle)));
+    let migration = options
+        .migrate
+        .then(|| follow_network(client.endpoints.clone(), Arc::clone(&client.metrics)))
+        .flatten();
+
+    for (source, target) in mappings {
+        client.add_forward(source, target).await?;
+    }
+    for (source, listener, target) in &options.listeners {
+        client.add_forward_listener(source, listener.try_clone()?, target)?;
+    }
+
+    if let Some(addr) = options.metrics {
+        metrics::serve(
+            addr,
+            Arc::clone(&client.metrics),
+            Arc::clone(&client.streams),
+        )
+        .await?;
+    }
+
+    if let Some(control) = &options.control {
+        #[cfg(unix)]
+        {
+            let listener = crate::control::bind(control)?;
+            tokio::spawn(crate::control::serve(listener, Arc::clone(&client)));
+        }
+        #[cfg(not(unix))]
+        bail!("control sockets are not supported on this platform: {control:?}");
+    }
+
+    #[cfg(unix)]
+    let heartbeat = {
+        let to = match options.on_demand {
+            None => format!("connected to {}", client.target),
+            Some(_) => format!("forwarding to {} on demand", client.target),
+        };
+        options.notify.ready(&to);
+        let streams = Arc::clone(&client.streams);
+        options
+            .notify
+            .spawn_heartbeat(move || format!("{to}, forwarding {} streams", streams.len()))
+    };
+
+    let lost = async {
+        if supervisors.is_empty() {
+            future::pending().await
+        } else {
+            future::select_all(supervisors.iter_mut()).await.0
+        }
+    };
+    select! {
+        lost = lost => return Err(lost?),
+        () = shutdown => (),
+    }
+    for supervisor in supervisors {
+        supervisor.abort();
+    }
+    if let Some(idler) = idler {
+        idler.abort();
+    }
+    if let Some(migration) = migration {
+        migration.abort();
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.abort();
+        }
+        options
+            .notify
+            .stopping(&format!("draining {} streams", client.streams.len()));
+    }
+    client.shutdown(options.drain_timeout).await;
+    Ok(())
+}
+
+/// a connection to the server, and the local listeners feeding it
+pub struct Client {
+    // one for each of the pool's connections, each on its own socket: with their own source
+    // ports, ECMP routes and load balancers hashing the 4-tuple can spread them out
+    endpoints: Vec<Endpoint>,
+    target: String,
+    server_name: String,
+    server_pin: Option<Vec<u8>>,
+    // none until connected, when on demand
+    pool: Vec<Mutex<Option<Slot>>>,
+    on_demand: Option<Duration>,
+    // held while connecting on demand, or hanging up, so the two don't cross
+    dialing: tokio::sync::Mutex<()>,
+    // each time it connects on demand
+    dialed: Notify,
+    // while connecting the rest of a pool in the background, on demand
+    redialing: AtomicBool,
+    scheduler: Scheduler,
+    // for round robin
+    next: AtomicUsize,
+    forwards: Mutex<HashMap<SocketAddr, Forward>>,
+    streams: Arc<Streams>,
+    metrics: Arc<Metrics>,
+    extensions: Extensions,
+    bandwidth: Option<Arc<Shaper>>,
+    // by source, as given to `add_forward`
+    shapes: HashMap<String, Shape>,
+    shapers: HashMap<String, Arc<Shaper>>,
+}
+
+struct Forward {
+    target: String,
+    listener: JoinHandle<()>,
+}
+
+/// one of the pool's connections, and how many of our streams are on it
+#[derive(Clone)]
+struct Slot {
+    conn: Connection,
+    confirmed: Confirmed,
+    streams: Arc<AtomicUsize>,
+}
+
+impl Slot {
+    fn new(conn: Connection, confirmed: Confirmed) -> Self {
+        Slot {
+            conn,
+            confirmed,
+            streams: Arc::default(),
+        }
+    }
+}
+
+/// resolves once a connection's handshake is complete: until then, it may be resuming in
+/// 0-RTT, where anything sent could be replayed, so no 'con1' goes before
+#[derive(Clone)]
+struct Confirmed(Shared<BoxFuture<'static, Result<(), Arc<Error>>>>);
+
+impl Confirmed {
+    async fn wait(&self) -> Result<()> {
+        self.0.clone().await.map_err(|e| anyhow!("{e:#}"))
+    }
+}
+
+impl Client {
+    /// with `pool` already connected, or not, over `endpoints`, one each
+    fn new(
+        target: String,
+        endpoints: Vec<Endpoint>,
+        pool: Vec<Option<Slot>>,
+        options: &Options,
+        metrics: Arc<Metrics>,
+    ) -> Arc<Self> {
+        Arc::new(Client {
+            endpoints,
+            target,
+            server_name: options.server_name.to_string(),
+            server_pin: options.server_pin.clone(),
+            pool: pool.into_iter().map(Mutex::new).collect(),
+            on_demand: options.on_demand,
+            dialing: tokio::sync::Mutex::new(()),
+            dialed: Notify::new(),
+            redialing: AtomicBool::new(false),
+            scheduler: options.scheduler,
+            next: AtomicUsize::new(0),
+            forwards: Mutex::new(HashMap::new()),
+            streams: Arc::new(Streams::default()),
+            metrics,
+            extensions: options.extensions.clone(),
+            bandwidth: options.bandwidth.map(|rate| Shaper::new("global", rate)),
+            shapes: options.shapes.clone(),
+            shapers: Shaper::for_shapes("forward", &options.shapes),
+        })
+    }
+
+    /// start listening on (every resolution of) `source`, forwarding accepted connections to
+    /// `target`; if any of them fails, none are left listening
+    pub async fn add_forward(
+        self: &Arc<Self>,
+        source: &str,
+        target: &str,
+    ) -> Result<Vec<SocketAddr>> {
+        let source_name = source;
+        let mut added = Vec::new();
+        let res = async {
+            for source in lookup_host(source).await? {
+                ensure!(
+                    !self
+                        .forwards
+                        .lock()
+                        .expect("poisoned")
+                        .contains_key(&source),
+                    "already forwarding from {source}"
+                );
+                let bind = TcpListener::bind(source)
+                    .await
+                    .with_context(|| anyhow!("binding {source}"))?;
+                self.forward(source, bind, target, self.shaping(source_name))?;
+                added.push(source);
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = res {
+            let mut forwards = self.forwards.lock().expect("poisoned");
+            for source in added {
+                if let Some(forward) = forwards.remove(&source) {
+                    forward.listener.abort();
+                }
+            }
+            return Err(e);
+        }
+        Ok(added)
+    }
+
+    /// as `add_forward`, but from a listener someone else bound, e.g. systemd; `name` is
+    /// the source it stands for
+    pub fn add_forward_listener(
+        self: &Arc<Self>,
+        name: &str,
+        listener: std::net::TcpListener,
+        target: &str,
+    ) -> Result<SocketAddr> {
+        let source = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        self.forward(
+            source,
+            TcpListener::from_std(listener)?,
+            target,
+            self.shaping(name),
+        )?;
+        Ok(source)
+    }
+
+    fn forward(
+        self: &Arc<Self>,
+        source: SocketAddr,
+        bind: TcpListener,
+        target: &str,
+        shaping: Shaping,
+    ) -> Result<()> {
+        let establish = Establish {
+            protocol: b't',
+            address_port: target.to_string(),
+            flags: SUPPORTED_FLAGS,
+            // so the server's side of the stream is ordered like ours
+            priority: shaping.priority,
+        };
+        // checked again here, as someone else may have added it while we were binding
+        let mut forwards = self.forwards.lock().expect("poisoned");
+        let Entry::Vacant(entry) = forwards.entry(source) else {
+            bail!("already forwarding from {source}");
+        };
+        let listener = tokio::spawn(Arc::clone(self).accept_proxies(bind, establish, shaping));
+        entry.insert(Forward {
+            target: target.to_string(),
+            listener,
+        });
+        Ok(())
+    }
+
+    /// the limits, and priority, for streams from the forward named `source`
+    fn shaping(&self, source: &str) -> Shaping {
+        let mut shaping = Shaping::new(Arc::clone(&self.metrics));
+        if let Some(priority) = self.shapes.get(source).and_then(|shape| shape.priority) {
+            shaping.priority = priority;
+        }
+        shaping.limit(self.shapers.get(source));
+        shaping.limit(self.bandwidth.as_ref());
+        shaping
+    }
+
+    /// stop listening on `source`; connections already accepted are left running
+    pub async fn remove_forward(&self, source: &str) -> Result<Vec<SocketAddr>> {
+        let mut removed = Vec::new();
+        for source in lookup_host(source).await? {
+            if let Some(forward) = self.forwards.lock().expect("poisoned").remove(&source) {
+                forward.listener.abort();
+                removed.push(source);
+            }
+        }
+        ensure!(!removed.is_empty(), "not forwarding from {source}");
+        Ok(removed)
     }
 
+    pub fn forwards(&self) -> Vec<(SocketAddr, String)> {
+        let mut forwards = self
+            .forwards
+            .lock()
+            .expect("poisoned")
+            .iter()
+            .map(|(source, forward)| (*source, forward.target.to_string()))
+            .collect::<Vec<_>>();
+        forwards.sort();
+        forwards
+    }
+
+    pub fn streams(&self) -> &Streams {
+        &self.streams
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// the pool's connections, and how many streams each has open; none while hung up,
+    /// when on demand
+    pub fn connections(&self) -> Vec<(Connection, usize)> {
+        self.slots()
+            .into_iter()
+            .map(|slot| (slot.conn, slot.streams.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    fn slots(&self) -> Vec<Slot> {
+        self.pool
+            .iter()
+            .filter_map(|slot| slot.lock().expect("poisoned").clone())
+            .collect()
+    }
+
+    /// those of the pool that aren't connected, or are, but lost
+    fn missing(&self) -> Vec<usize> {
+        (0..self.pool.len())
+            .filter(|&index| {
+                self.pool[index]
+                    .lock()
+                    .expect("poisoned")
+                    .as_ref()
+                    .is_none_or(|slot| slot.conn.close_reason().is_some())
+            })
+            .collect()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.slots()
+            .iter()
+            .any(|slot| slot.conn.close_reason().is_none())
+    }
+
+    /// the connection a new stream to `target` should go over, connecting first if it's
+    /// on demand, and isn't
+    async fn pick(self: &Arc<Self>, target: &str) -> Result<Slot> {
+        // `dial` checks again, once it has the lock; with any of the pool up, the stream
+        // goes over that, rather than waiting on some member that may be unreachable
+        let _dialing = match self.on_demand {
+            Some(_) if !self.is_connected() => {
+                let dialing = self.dialing.lock().await;
+                self.dial().await?;
+                Some(dialing)
+            }
+            Some(_) if !self.missing().is_empty() => {
+                self.redial();
+                None
+            }
+            _ => None,
+        };
+        // even just dialed, a supervisor can have forgotten it since
+        let slots = self.slots();
+        ensure!(!slots.is_empty(), "not connected to {}", self.target);
+        // any still being replaced are skipped, unless that's all of them
+        let mut live = slots
+            .iter()
+            .filter(|slot| slot.conn.close_reason().is_none())
+            .collect::<Vec<_>>();
+        if live.is_empty() {
+            live = slots.iter().collect();
+        }
+        let picked = match self.scheduler {
+            Scheduler::RoundRobin => live[self.next.fetch_add(1, Ordering::Relaxed) % live.len()],
+            Scheduler::LeastStreams => live
+                .iter()
+                .min_by_key(|slot| slot.streams.load(Ordering::Relaxed))
+                .expect("never empty"),
+            Scheduler::TargetHash => {
+                let mut hasher = DefaultHasher::new();
+                target.hash(&mut hasher);
+                live[hasher.finish() as usize % live.len()]
+            }
+        };
+        Ok(picked.clone())
+    }
+
+    /// `dial` in the background, unless that's already happening
+    fn redial(self: &Arc<Self>) {
+        if self.redialing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let client = Arc::clone(self);
+        tokio::spawn(async move {
+            let dialing = client.dialing.lock().await;
+            // hung up meanwhile, so the next stream connects it all again
+            if client.is_connected() {
+                // each failure is logged; the next stream tries again
+                let _ = client.dial().await;
+            }
+            drop(dialing);
+            client.redialing.store(false, Ordering::Release);
+        });
+    }
+
+    /// connect any of the pool that isn't, for `on_demand`; fails if none of it is, after
+    async fn dial(self: &Arc<Self>) -> Result<()> {
+        let missing = self.missing();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        info!("connecting to {} on demand", self.target);
+        let options = Options {
+            server_name: self.server_name.to_string(),
+            server_pin: self.server_pin.clone(),
+            ..Default::default()
+        };
+        let dialed = future::join_all(missing.iter().map(|&index| {
+            connect(
+                &self.endpoints[index],
+                &self.target,
+                &options,
+                &self.metrics,
+            )
+        }))
+        .await;
+        let mut failed = None;
+        for (index, dialed) in missing.into_iter().zip(dialed) {
+            match dialed {
+                Ok((conn, confirmed)) => {
+                    *self.pool[index].lock().expect("poisoned") = Some(Slot::new(conn, confirmed));
+                    tokio::spawn(Arc::clone(self).supervise(index));
+                }
+                Err(e) => {
+                    self.metrics.handshake_failed();
+                    warn!("connecting connection {index} on demand: {e:#}");
+                    failed = Some(e);
+                }
+            }
+        }
+        self.dialed.notify_one();
+        match failed {
+            Some(e) if !self.is_connected() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// for `on_demand`: once there have been no streams for `idle`, hang up
+    async fn hang_up_when_idle(self: Arc<Self>, idle: Duration) {
+        loop {
+            if !self.is_connected() {
+                self.dialed.notified().await;
+                continue;
+            }
+            self.streams.drained().await;
+            let started = self.streams.started();
+            sleep(idle).await;
+            let _dialing = self.dialing.lock().await;
+            // any started meanwhile may be waiting to pick a connection
+            if !self.streams.is_empty() || self.streams.started() != started {
+                continue;
+            }
+            let slots = self
+                .pool
+                .iter()
+                .filter_map(|slot| slot.lock().expect("poisoned").take())
+                .collect::<Vec<_>>();
+            if !slots.is_empty() {
+                info!("no streams for {idle:?}, hanging up");
+            }
+            for slot in slots {
+                slot.conn.close(CLOSE_DONE.into(), b"idle");
+            }
+        }
+    }
+
+    /// stop accepting, wait for established streams to finish, then hang up
+    pub async fn shutdown(&self, drain_timeout: Duration) {
+        for (_, forward) in self.forwards.lock().expect("poisoned").drain() {
+            forward.listener.abort();
+        }
+        info!("shutting down, draining {} streams", self.streams.len());
+        if timeout(drain_timeout, self.streams.drained())
+            .await
+            .is_err()
+        {
+            warn!(
+                "drain timeout expired, abandoning {} streams",
+                self.streams.len()
+            );
+        }
+        for endpoint in &self.endpoints {
+            endpoint.close(CLOSE_DONE.into(), b"client shutting down");
+        }
+        future::join_all(self.endpoints.iter().map(Endpoint::wait_idle)).await;
+    }
+
+    /// keep the pool's connection `index` going: move to a new connection when the server
+    /// is going away, and, in a pool, when it's lost; returns why it couldn't. On demand,
+    /// just forget it then, and let the next stream connect again
+    async fn supervise(self: Arc<Self>, index: usize) -> Error {
+        let mut backoff = RECONNECT_BACKOFF;
+        loop {
+            let Some(conn) = (self.pool[index].lock().expect("poisoned"))
+                .as_ref()
+                .map(|slot| slot.conn.clone())
+            else {
+                return anyhow!("hung up");
+            };
+            let going_away = self.accept_control(&conn).await;
+            if self.on_demand.is_some() {
+                let mut slot = self.pool[index].lock().expect("poisoned");
+                // unless it's been hung up, or replaced, already
+                if slot
+                    .as_ref()
+                    .is_some_and(|slot| slot.conn.stable_id() == conn.stable_id())
+                {
+                    *slot = None;
+                    match conn.close_reason() {
+                        None => info!("server is going away, reconnecting when needed"),
+                        Some(lost) => {
+                            warn!("connection {index} to server lost, reconnecting when needed: {lost}")
+                        }
+                    }
+                }
+                return anyhow!("connection {index} done with");
+            }
+            let why = if going_away {
+                info!("server is going away, reconnecting");
+                "server went away"
+            } else {
+                let lost = conn.closed().await;
+                if self.pool.len() == 1 {
+                    return Error::new(lost).context("connection to server lost");
+                }
+                warn!("connection {index} to server lost, reconnecting: {lost}");
+                "connection was lost"
+            };
+
+            // established streams stay where they are
+            let options = Options {
+                server_name: self.server_name.to_string(),
+                server_pin: self.server_pin.clone(),
+                ..Default::default()
+            };
+            loop {
+                let endpoint = &self.endpoints[index];
+                let connected = match connect(endpoint, &self.target, &options, &self.metrics).await
+                {
+                    // if it's resuming, streams can queue on it while the handshake finishes
+                    Ok((replacement, confirmed)) => {
+                        *self.pool[index].lock().expect("poisoned") =
+                            Some(Slot::new(replacement, confirmed.clone()));
+                        confirmed.wait().await
+                    }
+                    Err(e) => Err(e),
+                };
+                match connected {
+                    Ok(()) => {
+                        backoff = RECONNECT_BACKOFF;
+                        break;
+                    }
+                    Err(e) if self.pool.len() == 1 => {
+                        self.metrics.handshake_failed();
+                        error!("reconnecting after {why}: {:?}", e);
+                        let lost = conn.closed().await;
+                        return Error::new(lost).context("connection to server lost");
+                    }
+                    Err(e) => {
+                        self.metrics.handshake_failed();
+                        warn!("reconnecting connection {index} after {why}: {e:#}; retrying in {backoff:?}");
+                        sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    }
+                }
+            }
+        }
+    }
+
+    /// watch the server-initiated streams, which currently only carry the 'gway' message;
+    /// true if that's what we got, false if the connection's gone
+    async fn accept_control(&self, conn: &Connection) -> bool {
+        while let Ok(mut control) = conn.accept_uni().await {
+            match HeaderHeader::from(&mut control, Version::negotiated(conn)).await {
+                Ok(hh) if &hh.four_cc == b"gway" => return true,
+                Ok(hh) => warn!("unsupported server message: {:?}", hh),
+                Err(e) => warn!("reading server message: {:?}", e),
+            }
+        }
+        false
+    }
+
+    async fn accept_proxies(
+        self: Arc<Self>,
+        bind: TcpListener,
+        establish: Establish,
+        shaping: Shaping,
+    ) {
+        loop {
+            let (accepted, addr) = match bind.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("accepting on {:?}: {:?}", bind.local_addr(), e);
+                    return;
+                }
+            };
+            let client = Arc::clone(&self);
+            let metrics = Arc::clone(&self.metrics);
+            let extensions = self.extensions.clone();
+            let establish = establish.clone();
+            let shaping = shaping.clone();
+            let guard = self
+                .streams
+                .register(addr.to_string(), establish.address_port.to_string());
+            tokio::spawn(async move {
+                let (plain_from, plain_to) = accepted.into_split();
+                let mut plain_from = Counting::new(plain_from, &guard.info.read);
+                let mut plain_to = Counting::new(plain_to, &guard.info.written);
+                let res = match client.pick(&establish.address_port).await {
+                    Ok(slot) => {
+                        slot.streams.fetch_add(1, Ordering::Relaxed);
+                        let res = handle_proxy_connection(
+                            &mut plain_from,
+                            &mut plain_to,
+                            slot.conn.clone(),
+                            &slot.confirmed,
+                            &establish,
+                            &extensions,
+                            &shaping,
+                        )
+                        .await;
+                        slot.streams.fetch_sub(1, Ordering::Relaxed);
+                        res
+                    }
+                    Err(e) => Err(e),
+                };
+                if wants_tcp_reset(&res) {
+                    reset_tcp(plain_from.into_inner(), plain_to.into_inner());
+                }
+                if let Err(e) = res {
+                    if let Some(refused) = e.downcast_ref::<Refused>() {
+                        metrics.connect_failed(refused.code);
+                    }
+                    error!("processing connection from {:?}: {:?}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+/// open a single stream to `address_port`, and pipe it to our own stdin/stdout,
+/// e.g. for use as an ssh `ProxyCommand`
+pub async fn stdio(
+    target: String,
+    certs: &ClientCerts,
+    address_port: String,
+    options: &Options,
+) -> Result<()> {
+    let endpoint = endpoint(&client_config(certs, &options.transport)?)?;
+    let metrics = Arc::new(Metrics::default());
+    let (conn, confirmed) = connect(&endpoint, &target, options, &metrics).await?;
+    let migration = options
+        .migrate
+        .then(|| follow_network(vec![endpoint.clone()], metrics))
+        .flatten();
+
+    let establish = Establish {
+        protocol: b't',
+        address_port,
+        flags: SUPPORTED_FLAGS,
+        priority: 0,
+    };
+    let mut shaping = Shaping::default();
+    shaping.limit(
+        options
+            .bandwidth
+            .map(|rate| Shaper::new("global", rate))
+            .as_ref(),
+    );
+    handle_proxy_connection(
+        tokio::io::stdin(),
+        tokio::io::stdout(),
+        conn.clone(),
+        &confirmed,
+        &establish,
+        &options.extensions,
+        &shaping,
+    )
+    .await?;
+
+    if let Some(migration) = migration {
+        migration.abort();
+    }
+    conn.close(CLOSE_DONE.into(), b"stdio finished");
+    endpoint.wait_idle().await;
+
+    Ok(())
+}
+
+fn client_config(certs: &ClientCerts, transport: &Transport) -> Result<quinn::ClientConfig> {
     let mut roots = rustls::RootCertStore::empty();
     roots.add(&certs.server_cert)?;
 
     let mut client_crypto = rustls::ClientConfig::builder()
         .with_safe_defaults()
         .with_root_certificates(roots)
-        .with_single_cert(vec![certs.client_cert.clone()], certs.client_key.clone())?;
+        .with_client_auth_cert(vec![certs.client_cert.clone()], certs.client_key.clone())?;
 
     client_crypto.alpn_protocols = alpn_protocols();
+    // resume with tickets from earlier connections, made by any endpoint sharing this config:
+    // rustls has no way to save them, so they're lost on exit
+    client_crypto.enable_early_data = true;
 
-    let mut endpoint = quinn::Endpoint::client(
+    let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+    client_config.transport_config(Arc::new(transport.config()?));
+    Ok(client_config)
+}
+
+/// on a socket of its own, so a port of its own
+fn endpoint(config: &quinn::ClientConfig) -> Result<Endpoint> {
+    let mut endpoint = Endpoint::client(
         "[::]:0"
             .parse()
             .context("producing 'all addresses' address")?,
     )?;
-    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(client_crypto)));
+    endpoint.set_default_client_config(config.clone());
+    Ok(endpoint)
+}
+
+/// move `endpoint` to a fresh socket, on a new port, e.g. after the network's changed
+/// underneath it; its connections carry on over the new path, once the server's checked it
+fn rebind(endpoint: &Endpoint) -> Result<SocketAddr> {
+    let local = endpoint.local_addr()?;
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(local.ip(), 0))
+        .context("binding a fresh socket")?;
+    let addr = socket.local_addr()?;
+    endpoint.rebind(socket)?;
+    Ok(addr)
+}
+
+/// `rebind` each of `endpoints` whenever the local addresses, or routes, change
+#[cfg(target_os = "linux")]
+fn follow_network(endpoints: Vec<Endpoint>, metrics: Arc<Metrics>) -> Option<JoinHandle<()>> {
+    let mut changes = match crate::netwatch::Changes::subscribe() {
+        Ok(changes) => changes,
+        Err(e) => {
+            warn!("not following network changes: {e:#}");
+            return None;
+        }
+    };
+    Some(tokio::spawn(async move {
+        loop {
+            if let Err(e) = changes.changed().await {
+                warn!("no longer following network changes: {e:#}");
+                return;
+            }
+            for endpoint in &endpoints {
+                match rebind(endpoint) {
+                    Ok(addr) => {
+                        metrics.rebound();
+                        info!("network changed, moved to {addr}");
+                    }
+                    Err(e) => warn!("network changed, but moving failed: {e:#}"),
+                }
+            }
+        }
+    }))
+}
+
+/// there's no watching for changes here; QUIC still copes with the address changing
+/// under an existing socket, e.g. a NAT rebinding
+#[cfg(not(target_os = "linux"))]
+fn follow_network(_endpoints: Vec<Endpoint>, _metrics: Arc<Metrics>) -> Option<JoinHandle<()>> {
+    None
+}
+
+/// resuming in 0-RTT, if an earlier connection left us a ticket, in which case it's returned
+/// before the handshake is complete: wait for it to be `Confirmed` before doing anything
+async fn connect(
+    endpoint: &Endpoint,
+    target: &str,
+    options: &Options,
+    metrics: &Arc<Metrics>,
+) -> Result<(Connection, Confirmed)> {
+    let targets: Vec<SocketAddr> = target.to_socket_addrs()?.collect();
+    if targets.is_empty() {
+        bail!("{:?} resolved to nowhere", target);
+    }
 
     if 1 != targets.len() {
         warn!("ignoring some target addresses from: {:?}", targets);
     }
-    let conn = endpoint.connect(targets[0], "localhost")?.await?;
+    let (conn, resuming) = match endpoint.connect(targets[0], &options.server_name)?.into_0rtt() {
+        Ok((conn, accepted)) => {
+            debug!("resuming our session with {target}, in 0-RTT");
+            (conn, Some(accepted))
+        }
+        Err(connecting) => match connecting.await {
+            Ok(conn) => (conn, None),
+            Err(e) if no_shared_version(&e) => return Err(e).with_context(|| {
+                anyhow!(
+                    "{target} speaks no protocol version we do ({}); is it a much newer, or much older, qpiped?",
+                    describe_versions()
+                )
+            }),
+            Err(e) => return Err(e.into()),
+        },
+    };
+    let resumed = resuming.is_some();
+    let confirmed = confirm(
+        conn.clone(),
+        resuming,
+        target.to_string(),
+        options.server_pin.clone(),
+        Arc::clone(metrics),
+    );
+    let confirmed = Confirmed(confirmed.map(|res| res.map_err(Arc::new)).boxed().shared());
+    if !resumed {
+        confirmed.wait().await?;
+    }
+    Ok((conn, confirmed))
+}
 
-    let mut proxies = Vec::new();
-    for (source, target) in mappings {
-        for source in source.to_socket_addrs()? {
-            let establish = Establish {
-                protocol: b't',
-                address_port: target.to_string(),
-            };
-            proxies.push(tokio::spawn(spawn_proxies(conn.clone(), source, establish)));
+/// wait for the handshake to complete, if it's resuming, then check it's the server we expect
+async fn confirm(
+    conn: Connection,
+    resuming: Option<ZeroRttAccepted>,
+    target: String,
+    pin: Option<Vec<u8>>,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    if let Some(accepted) = resuming {
+        // the server may refuse early data; that's fine, as we never send any that matters
+        let accepted = accepted.await;
+        if let Some(reason) = conn.close_reason() {
+            return Err(Error::new(reason).context(format!("resuming our session with {target}")));
+        }
+        debug!("resumed our session with {target}, early data accepted: {accepted}");
+    }
+    if let Some(pin) = &pin {
+        let presented = conn
+            .peer_identity()
+            .and_then(|certs| certs.downcast::<Vec<rustls::Certificate>>().ok())
+            .and_then(|certs| certs.first().map(certs::pin));
+        if presented.as_ref() != Some(pin) {
+            conn.close(CLOSE_DONE.into(), b"unexpected certificate");
+            bail!("{target} presented a certificate which doesn't match the pin");
         }
     }
+    metrics.connection_opened(certs::peer_fingerprint(&conn), &conn);
+    Ok(())
+}
+
+async fn handle_proxy_connection(
+    plain_from: impl AsyncRead + Unpin,
+    plain_to: impl AsyncWrite + Unpin,
+    framed: Connection,
+    confirmed: &Confirmed,
+    establish: &Establish,
+    extensions: &Extensions,
+    shaping: &Shaping,
+) -> Result<()> {
+    confirmed.wait().await?;
+    let version = Version::negotiated(&framed);
+    let identity = certs::peer_fingerprint(&framed);
+    let dispatch = extensions.dispatch(Seen::Stream {
+        identity: &identity,
+        peer: framed.remote_address(),
+    });
+    let (mut framed_to, mut framed_from) = framed.open_bi().await?;
 
-    try_join_all(proxies).await?;
+    wire::write_establish(&mut framed_to, version, establish).await?;
+    // TODO: handle ping?
+    let flags = wire::read_okay(&mut framed_from, version, dispatch).await?;
 
-    Ok(())
+    splice(
+        version,
+        flags & FLAG_RAW != 0,
+        dispatch,
+        shaping,
+        plain_from,
+        plain_to,
+        &mut framed_to,
+        &mut framed_from,
+    )
+    .await
 }
 
-async fn spawn_proxies(framed: Connection, source: SocketAddr, establish: Establish) -> Result<()> {
-    let bind = TcpListener::bind(source).await?;
+/// a server on localhost, and certificates for a client of it
+#[cfg(test)]
+pub(crate) struct TestServer {
+    pub(crate) certs: ClientCerts,
+    pub(crate) addr: SocketAddr,
+    // its certificates, kept for a restart
+    dir: tempfile::TempDir,
+    stop: tokio::sync::oneshot::Sender<()>,
+    server: JoinHandle<Result<()>>,
+}
 
-    loop {
-        let (client, addr) = bind.accept().await?;
-        let framed = framed.clone();
-        let establish = establish.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_proxy_connection(client, framed, &establish).await {
-                error!("processing connection from {:?}: {:?}", addr, e);
+#[cfg(test)]
+impl TestServer {
+    pub(crate) fn start() -> Result<Self> {
+        Self::start_with(crate::server::Options {
+            drain_timeout: Duration::from_secs(1),
+            ..Default::default()
+        })
+    }
+
+    /// with `options`, besides where it listens
+    pub(crate) fn start_with(options: crate::server::Options) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let (ca, ca_key) = certs::ca(&dir, &certs::KeyStorage::default())?;
+        let (csr, client_key) = certs::generate_client_certs()?;
+        let client_cert = certs::mint_client(&ca, &ca_key, &certs::parse_client(&csr.0)?)?;
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let addr = socket.local_addr()?;
+        let (stop, server) = Self::serve(&dir, socket, options)?;
+        Ok(TestServer {
+            certs: ClientCerts {
+                server_cert: ca,
+                client_cert,
+                client_key,
+            },
+            addr,
+            dir,
+            stop,
+            server,
+        })
+    }
+
+    /// on `socket`, with the server certificate in `dir`, until stopped
+    fn serve(
+        dir: &tempfile::TempDir,
+        socket: std::net::UdpSocket,
+        options: crate::server::Options,
+    ) -> Result<(tokio::sync::oneshot::Sender<()>, JoinHandle<Result<()>>)> {
+        let storage = certs::KeyStorage::default();
+        let (server_chain, server_key) = certs::server(dir, &["localhost"], &storage)?;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(crate::server::run(
+            crate::server::Certs {
+                server_key,
+                server_chain,
+            },
+            Vec::new(),
+            crate::server::Options {
+                sockets: vec![socket],
+                ..options
+            },
+            async {
+                let _ = stopped.await;
+            },
+        ));
+        Ok((stop, server))
+    }
+
+    /// stop, and once it's drained, start again on the same address, with `options`
+    pub(crate) async fn restart(self, options: crate::server::Options) -> Result<Self> {
+        let _ = self.stop.send(());
+        self.server.await??;
+        // the old socket may take a moment to be let go of
+        let socket = timeout(Duration::from_secs(5), async {
+            loop {
+                match std::net::UdpSocket::bind(self.addr) {
+                    Ok(socket) => return socket,
+                    Err(_) => sleep(Duration::from_millis(10)).await,
+                }
             }
-        });
+        })
+        .await?;
+        let (stop, server) = Self::serve(&self.dir, socket, options)?;
+        Ok(TestServer {
+            stop,
+            server,
+            ..self
+        })
+    }
+
+    /// a connection to it, once the handshake's complete
+    pub(crate) async fn connect(&self) -> Result<Connection> {
+        let endpoint = endpoint(&client_config(&self.certs, &Transport::default())?)?;
+        let target = self.addr.to_string();
+        let (conn, confirmed) =
+            connect(&endpoint, &target, &Options::default(), &Arc::default()).await?;
+        confirmed.wait().await?;
+        Ok(conn)
+    }
+
+    pub(crate) async fn stop(self) -> Result<()> {
+        let _ = self.stop.send(());
+        self.server.await?
     }
 }
 
-async fn handle_proxy_connection(
-    plain: TcpStream,
-    framed: Connection,
+/// echoes everything sent to it
+#[cfg(test)]
+pub(crate) async fn echo_server() -> Result<SocketAddr> {
+    use tokio::io::AsyncWriteExt as _;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.into_split();
+                tokio::io::copy(&mut read, &mut write).await?;
+                write.shutdown().await
+            });
+        }
+    });
+    Ok(addr)
+}
+
+#[cfg(test)]
+pub(crate) fn establish_to(target: SocketAddr) -> Establish {
+    Establish {
+        protocol: b't',
+        address_port: target.to_string(),
+        flags: SUPPORTED_FLAGS,
+        priority: 0,
+    }
+}
+
+#[tokio::test]
+async fn test_rebind_mid_transfer() -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let server = TestServer::start()?;
+    let echo = echo_server().await?;
+    let endpoint = endpoint(&client_config(&server.certs, &Transport::default())?)?;
+    let (conn, confirmed) = connect(
+        &endpoint,
+        &server.addr.to_string(),
+        &Options::default(),
+        &Arc::default(),
+    )
+    .await?;
+    let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    let proxy = tokio::spawn({
+        let conn = conn.clone();
+        async move {
+            let (from, to) = tokio::io::split(theirs);
+            let extensions = Extensions::default();
+            let establish = establish_to(echo);
+            let shaping = Shaping::default();
+            handle_proxy_connection(
+                from,
+                to,
+                conn,
+                &confirmed,
+                &establish,
+                &extensions,
+                &shaping,
+            )
+            .await
+        }
+    });
+
+    // half before moving, half after, so both directions have to find the new path
+    const LEN: usize = 1024 * 1024;
+    let sent = (0..LEN).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let (mut ours_from, mut ours_to) = tokio::io::split(ours);
+    let (moved, rebound) = tokio::sync::oneshot::channel::<()>();
+    let writer = tokio::spawn({
+        let sent = sent.clone();
+        async move {
+            ours_to.write_all(&sent[..LEN / 2]).await?;
+            rebound.await?;
+            ours_to.write_all(&sent[LEN / 2..]).await?;
+            ours_to.shutdown().await?;
+            Ok::<_, Error>(())
+        }
+    });
+
+    let mut received = vec![0; LEN / 2];
+    ours_from.read_exact(&mut received).await?;
+    let before = endpoint.local_addr()?;
+    let after = rebind(&endpoint)?;
+    assert_ne!(before.port(), after.port());
+    moved.send(()).expect("writing");
+    timeout(
+        Duration::from_secs(10),
+        ours_from.read_to_end(&mut received),
+    )
+    .await??;
+    assert!(sent == received, "echoed something else");
+    writer.await??;
+    proxy.await??;
+
+    conn.close(CLOSE_DONE.into(), b"test finished");
+    server.stop().await
+}
+
+#[tokio::test]
+async fn test_resume_in_0rtt() -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let server = TestServer::start()?;
+    let echo = echo_server().await?;
+    let endpoint = endpoint(&client_config(&server.certs, &Transport::default())?)?;
+    let target = server.addr.to_string();
+    let metrics = Arc::new(Metrics::default());
+    let echo_over = |conn: Connection, confirmed: Confirmed| async move {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let (from, to) = tokio::io::split(theirs);
+        let (mut ours_from, mut ours_to) = tokio::io::split(ours);
+        let (establish, extensions) = (establish_to(echo), Extensions::default());
+        let shaping = Shaping::default();
+        let proxy = handle_proxy_connection(
+            from,
+            to,
+            conn,
+            &confirmed,
+            &establish,
+            &extensions,
+            &shaping,
+        );
+        let talk = async {
+            ours_to.write_all(b"hello").await?;
+            ours_to.shutdown().await?;
+            let mut received = Vec::new();
+            ours_from.read_to_end(&mut received).await?;
+            Ok::<_, Error>(received)
+        };
+        let (proxied, received) = tokio::join!(proxy, talk);
+        proxied?;
+        assert_eq!(b"hello", received?.as_slice());
+        Ok::<_, Error>(())
+    };
+
+    // nothing to resume yet, so the handshake's done by the time we have it
+    let (first, confirmed) = connect(&endpoint, &target, &Options::default(), &metrics).await?;
+    echo_over(first.clone(), confirmed).await?;
+    first.close(CLOSE_DONE.into(), b"test finished");
+
+    // its tickets allow early data, which the server accepts
+    let (early, accepted) = endpoint
+        .connect(server.addr, DEFAULT_SERVER_NAME)?
+        .into_0rtt()
+        .map_err(|_| anyhow!("no ticket to resume with"))?;
+    assert!(accepted.await, "early data refused");
+    early.close(CLOSE_DONE.into(), b"test finished");
+
+    // and the spare resumes too, with the stream waiting for the handshake
+    let (resumed, confirmed) = connect(&endpoint, &target, &Options::default(), &metrics).await?;
+    echo_over(resumed.clone(), confirmed).await?;
+    resumed.close(CLOSE_DONE.into(), b"test finished");
+
+    server.stop().await
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_on_demand() -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let server = TestServer::start()?;
+    let echo = echo_server().await?;
+    let dir = tempfile::tempdir()?;
+    let control = dir.path().join("control");
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let source = listener.local_addr()?;
+    let options = Options {
+        control: Some(control.clone()),
+        listeners: vec![("test".to_string(), listener, echo.to_string())],
+        on_demand: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let client = tokio::spawn(async move {
+        run(
+            server.addr.to_string(),
+            &server.certs,
+            &[],
+            &options,
+            async {
+                let _ = stopped.await;
+            },
+        )
+        .await?;
+        server.stop().await
+    });
+    let status = || async {
+        let lines = crate::control::request(&control, &crate::control::Request::Status).await?;
+        Ok::<_, Error>(lines[0].to_string())
+    };
+    let echo_once = || async {
+        let mut stream = tokio::net::TcpStream::connect(source).await?;
+        stream.write_all(b"hello").await?;
+        stream.shutdown().await?;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await?;
+        assert_eq!(b"hello", received.as_slice());
+        Ok::<_, Error>(())
+    };
+
+    // listening already, but not connected until the first stream
+    timeout(Duration::from_secs(5), async {
+        while !control.exists() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    assert!(status().await?.starts_with("not connected"));
+    echo_once().await?;
+    assert!(status().await?.starts_with("connected"));
+
+    // idle, so hung up, then connected again for the next
+    sleep(Duration::from_millis(500)).await;
+    assert!(status().await?.starts_with("not connected"));
+    echo_once().await?;
+    assert!(status().await?.starts_with("connected"));
+
+    let _ = stop.send(());
+    client.await?
+}
+
+#[tokio::test]
+async fn test_flagless_okay_is_empty() -> Result<()> {
+    let server = TestServer::start()?;
+    let echo = echo_server().await?;
+    let conn = server.connect().await?;
+    let version = Version::negotiated(&conn);
+    let (mut framed_to, mut framed_from) = conn.open_bi().await?;
+
+    // as a client from before flags sends it: no flags, and no priority
+    let address_port = echo.to_string();
+    let mut body = vec![b't', address_port.len() as u8];
+    body.extend_from_slice(address_port.as_bytes());
+    HeaderHeader {
+        four_cc: *b"con1",
+        data_len: body.len() as u64,
+    }
+    .write_all(&mut framed_to, version)
+    .await?;
+    framed_to.write_all(&body).await?;
+
+    let okay = HeaderHeader::from(&mut framed_from, version).await?;
+    assert_eq!(b"okay", &okay.four_cc);
+    assert_eq!(0, okay.data_len);
+
+    conn.close(CLOSE_DONE.into(), b"test finished");
+    server.stop().await
+}
+
+/// forward one connection accepted on `source` through `server`, as `accept_proxies` does
+#[cfg(test)]
+async fn forward_once(
+    server: &TestServer,
+    source: &TcpListener,
     establish: &Establish,
 ) -> Result<()> {
-    let (mut plain_from, mut plain_to) = plain.into_split();
-    let (mut framed_to, mut framed_from) = framed.open_bi().await?;
+    let endpoint = endpoint(&client_config(&server.certs, &Transport::default())?)?;
+    let target = server.addr.to_string();
+    let (conn, confirmed) =
+        connect(&endpoint, &target, &Options::default(), &Arc::default()).await?;
+    let (accepted, _) = source.accept().await?;
+    let (mut plain_from, mut plain_to) = accepted.into_split();
+    let res = handle_proxy_connection(
+        &mut plain_from,
+        &mut plain_to,
+        conn,
+        &confirmed,
+        establish,
+        &Extensions::default(),
+        &Shaping::default(),
+    )
+    .await;
+    if wants_tcp_reset(&res) {
+        reset_tcp(plain_from, plain_to);
+    }
+    res
+}
 
-    wire::write_establish(&mut framed_to, establish).await?;
-    // TODO: handle ping?
-    wire::read_okay(&mut framed_from).await?;
+#[tokio::test]
+async fn test_half_close() -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 
-    try_join!(
-        async {
-            copy_framing(&mut plain_from, &mut framed_to).await?;
-            HeaderHeader::finished().write_all(&mut framed_to).await?;
-            framed_to.shutdown().await?;
+    let server = TestServer::start()?;
+    let source = TcpListener::bind("127.0.0.1:0").await?;
+    let target = TcpListener::bind("127.0.0.1:0").await?;
+    // framed, where it's a 'fini', and raw, where it's the QUIC stream finishing; with each
+    // end finishing first
+    for (flags, app_first) in [(0, true), (0, false), (FLAG_RAW, true), (FLAG_RAW, false)] {
+        let establish = Establish {
+            flags,
+            ..establish_to(target.local_addr()?)
+        };
+        let talk = async {
+            let mut app = tokio::net::TcpStream::connect(source.local_addr()?).await?;
+            let (mut far, _) = target.accept().await?;
+            let (first, second) = match app_first {
+                true => (&mut app, &mut far),
+                false => (&mut far, &mut app),
+            };
+            first.write_all(b"request").await?;
+            first.shutdown().await?;
+            let mut received = Vec::new();
+            second.read_to_end(&mut received).await?;
+            assert_eq!(b"request", received.as_slice());
+
+            // the other way is still open
+            second.write_all(b"response").await?;
+            second.shutdown().await?;
+            let mut received = Vec::new();
+            first.read_to_end(&mut received).await?;
+            assert_eq!(b"response", received.as_slice());
+            Ok::<_, Error>(())
+        };
+        let (proxied, talked) = timeout(Duration::from_secs(10), async {
+            tokio::join!(forward_once(&server, &source, &establish), talk)
+        })
+        .await?;
+        proxied?;
+        talked?;
+    }
+    server.stop().await
+}
+
+#[tokio::test]
+async fn test_reset_travels() -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let server = TestServer::start()?;
+    let source = TcpListener::bind("127.0.0.1:0").await?;
+    let target = TcpListener::bind("127.0.0.1:0").await?;
+    for flags in [0, FLAG_RAW] {
+        let establish = Establish {
+            flags,
+            ..establish_to(target.local_addr()?)
+        };
+        let talk = async {
+            let mut app = tokio::net::TcpStream::connect(source.local_addr()?).await?;
+            app.write_all(b"hello").await?;
+            let (mut far, _) = target.accept().await?;
+            // once it's all connected
+            far.read_exact(&mut [0; 5]).await?;
+            // a zero linger makes the close a reset
+            far.set_linger(Some(Duration::ZERO))?;
+            drop(far);
+            let read = app.read(&mut [0; 16]).await;
+            assert_eq!(
+                Some(std::io::ErrorKind::ConnectionReset),
+                read.err().map(|e| e.kind())
+            );
             Ok::<_, Error>(())
+        };
+        let (proxied, talked) = timeout(Duration::from_secs(10), async {
+            tokio::join!(forward_once(&server, &source, &establish), talk)
+        })
+        .await?;
+        assert!(wants_tcp_reset(&proxied), "{proxied:?}");
+        talked?;
+    }
+    server.stop().await
+}
+
+/// a client with a pool of `connections` to `server`, and nothing running
+#[cfg(test)]
+async fn pooled_client(
+    server: &TestServer,
+    connections: usize,
+    scheduler: Scheduler,
+) -> Result<Arc<Client>> {
+    let config = client_config(&server.certs, &Transport::default())?;
+    let endpoints = (0..connections)
+        .map(|_| endpoint(&config))
+        .collect::<Result<Vec<_>>>()?;
+    let options = Options {
+        connections,
+        scheduler,
+        ..Default::default()
+    };
+    let target = server.addr.to_string();
+    let mut pool = Vec::new();
+    for endpoint in &endpoints {
+        let (conn, confirmed) = connect(endpoint, &target, &options, &Arc::default()).await?;
+        pool.push(Some(Slot::new(conn, confirmed)));
+    }
+    Ok(Client::new(
+        target,
+        endpoints,
+        pool,
+        &options,
+        Arc::default(),
+    ))
+}
+
+#[tokio::test]
+async fn test_schedulers() -> Result<()> {
+    let server = TestServer::start()?;
+    let ids = |client: &Client| -> Vec<usize> {
+        client
+            .slots()
+            .iter()
+            .map(|slot| slot.conn.stable_id())
+            .collect()
+    };
+
+    // round robin takes each in turn, skipping any that have been lost
+    let client = pooled_client(&server, 3, Scheduler::RoundRobin).await?;
+    let pool = ids(&client);
+    let mut picked = Vec::new();
+    for _ in 0..6 {
+        picked.push(client.pick("t:1").await?.conn.stable_id());
+    }
+    assert_eq!([pool.as_slice(), pool.as_slice()].concat(), picked);
+    client.slots()[1].conn.close(CLOSE_DONE.into(), b"lost");
+    let mut picked = Vec::new();
+    for _ in 0..4 {
+        picked.push(client.pick("t:1").await?.conn.stable_id());
+    }
+    assert!(!picked.contains(&pool[1]), "picked a lost connection");
+    assert_eq!(picked[..2], picked[2..]);
+
+    // least streams follows what's open on each
+    let client = pooled_client(&server, 3, Scheduler::LeastStreams).await?;
+    let pool = ids(&client);
+    for (slot, streams) in client.slots().iter().zip([2, 0, 1]) {
+        slot.streams.store(streams, Ordering::Relaxed);
+    }
+    assert_eq!(pool[1], client.pick("t:1").await?.conn.stable_id());
+    client.slots()[1].streams.store(3, Ordering::Relaxed);
+    assert_eq!(pool[2], client.pick("t:1").await?.conn.stable_id());
+
+    // target hash always picks the same for a target
+    let client = pooled_client(&server, 3, Scheduler::TargetHash).await?;
+    for target in ["a:1", "b:2", "c:3", "d:4"] {
+        let first = client.pick(target).await?.conn.stable_id();
+        for _ in 0..4 {
+            assert_eq!(first, client.pick(target).await?.conn.stable_id());
+        }
+    }
+
+    server.stop().await
+}
+
+/// passes udp between clients and a server, except for a flow that's been blocked
+#[cfg(test)]
+#[derive(Clone)]
+struct Relay {
+    addr: SocketAddr,
+    front: Arc<tokio::net::UdpSocket>,
+    // bytes from each client, so far
+    received: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+    blocked: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+#[cfg(test)]
+impl Relay {
+    async fn start(server: SocketAddr) -> Result<Self> {
+        let front = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+        let relay = Relay {
+            addr: front.local_addr()?,
+            front,
+            received: Arc::default(),
+            blocked: Arc::default(),
+        };
+        tokio::spawn(relay.clone().relay_up(server));
+        Ok(relay)
+    }
+
+    /// to `server`, from a socket of its own for each client
+    async fn relay_up(self, server: SocketAddr) -> Result<()> {
+        let mut upstreams = HashMap::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let (len, client) = self.front.recv_from(&mut buf).await?;
+            *self
+                .received
+                .lock()
+                .expect("poisoned")
+                .entry(client)
+                .or_default() += len;
+            if self.is_blocked(client) {
+                continue;
+            }
+            let upstream = match upstreams.entry(client) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let upstream = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+                    upstream.connect(server).await?;
+                    tokio::spawn(self.clone().relay_down(client, Arc::clone(&upstream)));
+                    entry.insert(upstream)
+                }
+            };
+            upstream.send(&buf[..len]).await?;
+        }
+    }
+
+    /// from the server, back to `client`
+    async fn relay_down(
+        self,
+        client: SocketAddr,
+        upstream: Arc<tokio::net::UdpSocket>,
+    ) -> Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = upstream.recv(&mut buf).await?;
+            if !self.is_blocked(client) {
+                self.front.send_to(&buf[..len], client).await?;
+            }
+        }
+    }
+
+    fn is_blocked(&self, client: SocketAddr) -> bool {
+        *self.blocked.lock().expect("poisoned") == Some(client)
+    }
+
+    fn received(&self) -> HashMap<SocketAddr, usize> {
+        self.received.lock().expect("poisoned").clone()
+    }
+
+    fn block(&self, client: Option<SocketAddr>) {
+        *self.blocked.lock().expect("poisoned") = client;
+    }
+}
+
+/// `len` bytes there and back over `stream`, to an echo server
+#[cfg(test)]
+async fn echo_over(stream: &mut tokio::net::TcpStream, len: usize) -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let sent = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut received = vec![0; len];
+    let (mut from, mut to) = stream.split();
+    tokio::try_join!(to.write_all(&sent), from.read_exact(&mut received))?;
+    ensure!(sent == received, "echoed something else");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_replaces_lost() -> Result<()> {
+    let server = TestServer::start()?;
+    let relay = Relay::start(server.addr).await?;
+    let echo = echo_server().await?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let source = listener.local_addr()?;
+    let dir = tempfile::tempdir()?;
+    let control = dir.path().join("control");
+    let options = Options {
+        control: Some(control.clone()),
+        listeners: vec![("test".to_string(), listener, echo.to_string())],
+        connections: 2,
+        // so a lost one's noticed quickly, and the other isn't mistaken for lost
+        transport: Transport {
+            idle_timeout: Some(Duration::from_secs(1)),
+            keep_alive: Some(Duration::from_millis(100)),
+            ..Default::default()
         },
-        async {
-            let res = copy_unframing(&mut framed_from, &mut plain_to).await;
-            plain_to.shutdown().await?;
-            res
+        migrate: false,
+        ..Default::default()
+    };
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let target = relay.addr.to_string();
+    let client = tokio::spawn(async move {
+        let shutdown = async {
+            let _ = stopped.await;
+        };
+        run(target, &server.certs, &[], &options, shutdown).await?;
+        server.stop().await
+    });
+
+    // a stream on one connection; lose the other
+    sleep(Duration::from_millis(200)).await;
+    let mut carrying = tokio::net::TcpStream::connect(source).await?;
+    let before = relay.received();
+    echo_over(&mut carrying, 256 * 1024).await?;
+    let after = relay.received();
+    let idle = *after
+        .keys()
+        .min_by_key(|client| after[client] - before.get(client).unwrap_or(&0))
+        .expect("connected");
+    relay.block(Some(idle));
+    sleep(Duration::from_secs(2)).await;
+    echo_over(&mut carrying, 1024).await?;
+
+    // it's replaced once it can be, and new streams can go over either
+    relay.block(None);
+    timeout(Duration::from_secs(20), async {
+        loop {
+            let status = crate::control::request(&control, &crate::control::Request::Status);
+            let replaced = status
+                .await?
+                .iter()
+                .filter(|line| line.starts_with("connection ") && line.contains("rtt"))
+                .count()
+                == 2;
+            if !replaced {
+                sleep(Duration::from_millis(200)).await;
+                continue;
+            }
+            let mut streams = Vec::new();
+            for _ in 0..4 {
+                streams.push(tokio::net::TcpStream::connect(source).await?);
+            }
+            let echoed = future::join_all(streams.iter_mut().map(|stream| echo_over(stream, 16)));
+            if echoed.await.iter().all(Result::is_ok) {
+                return Ok::<_, Error>(());
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await??;
+    echo_over(&mut carrying, 1024).await?;
+    assert!(!client.is_finished(), "lost a connection, and gave up");
+    drop(carrying);
+
+    let _ = stop.send(());
+    client.await?
+}
+
+#[tokio::test]
+async fn test_on_demand_skips_unreachable() -> Result<()> {
+    let server = TestServer::start()?;
+    let relay = Relay::start(server.addr).await?;
+    let transport = Transport {
+        idle_timeout: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+    // configs of their own, so the unreachable one has no session to resume in 0-RTT
+    let endpoints = (0..2)
+        .map(|_| endpoint(&client_config(&server.certs, &transport)?))
+        .collect::<Result<Vec<_>>>()?;
+    let unreachable = SocketAddr::from(([127, 0, 0, 1], endpoints[1].local_addr()?.port()));
+    relay.block(Some(unreachable));
+    let options = Options {
+        connections: 2,
+        on_demand: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let target = relay.addr.to_string();
+    let (conn, confirmed) = connect(&endpoints[0], &target, &options, &Arc::default()).await?;
+    let reachable = conn.stable_id();
+    let pool = vec![Some(Slot::new(conn, confirmed)), None];
+    let client = Client::new(target, endpoints, pool, &options, Arc::default());
+
+    // streams go over the one that's up while the other's handshake goes nowhere
+    for _ in 0..3 {
+        let picked = timeout(Duration::from_millis(500), client.pick("example.com:22")).await??;
+        assert_eq!(reachable, picked.conn.stable_id());
+    }
+    assert!(client.redialing.load(Ordering::Acquire));
+
+    // and once it's reachable, it's connected
+    relay.block(None);
+    timeout(Duration::from_secs(10), async {
+        while !client.missing().is_empty() {
+            client.pick("example.com:22").await?;
+            sleep(Duration::from_millis(100)).await;
         }
+        Ok::<_, Error>(())
+    })
+    .await??;
+    for slot in client.slots() {
+        slot.conn.close(CLOSE_DONE.into(), b"test finished");
+    }
+    server.stop().await
+}
+
+#[tokio::test]
+async fn test_drain_and_go_away() -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let drain_timeout = Duration::from_secs(5);
+    let options = || crate::server::Options {
+        drain_timeout,
+        ..Default::default()
+    };
+    let server = TestServer::start_with(options())?;
+    let echo = echo_server().await?;
+    let extensions = Extensions::default();
+    let client = pooled_client(&server, 2, Scheduler::RoundRobin).await?;
+    let old = client
+        .slots()
+        .iter()
+        .map(|slot| slot.conn.stable_id())
+        .collect::<Vec<_>>();
+    for index in 0..client.pool.len() {
+        tokio::spawn(Arc::clone(&client).supervise(index));
+    }
+    let echo_once = |slot: Slot| async move {
+        let mut received = Vec::new();
+        handle_proxy_connection(
+            b"hello".as_slice(),
+            &mut received,
+            slot.conn,
+            &slot.confirmed,
+            &establish_to(echo),
+            &Extensions::default(),
+            &Shaping::default(),
+        )
+        .await?;
+        ensure!(b"hello" == received.as_slice(), "echoed something else");
+        Ok(())
+    };
+
+    // a transfer under way
+    let slot = client.pick("t:1").await?;
+    let (ours, theirs) = tokio::io::duplex(1024);
+    let transfer = tokio::spawn(async move {
+        let (from, to) = tokio::io::split(theirs);
+        let (establish, extensions) = (establish_to(echo), Extensions::default());
+        let shaping = Shaping::default();
+        handle_proxy_connection(
+            from,
+            to,
+            slot.conn,
+            &slot.confirmed,
+            &establish,
+            &extensions,
+            &shaping,
+        )
+        .await
+    });
+    let (mut ours_from, mut ours_to) = tokio::io::split(ours);
+    ours_to.write_all(b"before").await?;
+    ours_from.read_exact(&mut [0; 6]).await?;
+
+    // and a stream the server's accepted, but which hasn't said where it's going yet
+    let waiting = client.pick("t:1").await?.conn;
+    let version = Version::negotiated(&waiting);
+    let (mut framed_to, mut framed_from) = waiting.open_bi().await?;
+    let mut con1 = Vec::new();
+    wire::write_establish(&mut con1, version, &establish_to(echo)).await?;
+    let (start, rest) = con1.split_at(con1.len() - 1);
+    framed_to.write_all(start).await?;
+    sleep(Duration::from_millis(100)).await;
+
+    let started = std::time::Instant::now();
+    let restarted = tokio::spawn(server.restart(options()));
+
+    // told to go away, new streams go over new connections, while the old ones drain
+    timeout(Duration::from_secs(2), async {
+        while old.contains(&client.pick("t:1").await?.conn.stable_id()) {
+            sleep(Duration::from_millis(10)).await;
+        }
+        Ok::<_, Error>(())
+    })
+    .await??;
+
+    ours_to.write_all(b"after").await?;
+    ours_to.shutdown().await?;
+    let mut received = Vec::new();
+    ours_from.read_to_end(&mut received).await?;
+    assert_eq!(b"after", received.as_slice());
+    transfer.await??;
+
+    framed_to.write_all(rest).await?;
+    let flags = wire::read_okay(
+        &mut framed_from,
+        version,
+        extensions.dispatch(Seen::Package),
+    )
+    .await?;
+    let mut received = Vec::new();
+    splice(
+        version,
+        flags & FLAG_RAW != 0,
+        extensions.dispatch(Seen::Package),
+        &Shaping::default(),
+        b"late".as_slice(),
+        &mut received,
+        &mut framed_to,
+        &mut framed_from,
+    )
+    .await?;
+    assert_eq!(b"late", received.as_slice());
+
+    let server = restarted.await??;
+    assert!(started.elapsed() < drain_timeout, "drain timed out");
+
+    // and they're served by whatever's there next
+    timeout(Duration::from_secs(15), async {
+        while echo_once(client.pick("t:1").await?).await.is_err() {
+            sleep(Duration::from_millis(100)).await;
+        }
+        Ok::<_, Error>(())
+    })
+    .await??;
+
+    server.stop().await
+}
+
+#[tokio::test]
+async fn test_no_shared_version() -> Result<()> {
+    // a server from the future, speaking only versions we've never heard of
+    let cert = rcgen::generate_simple_self_signed(vec![DEFAULT_SERVER_NAME.to_string()])?;
+    let cert_der = rustls::Certificate(cert.serialize_der()?);
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der.clone()], key.clone())?;
+    server_crypto.alpn_protocols = vec![b"qpipe/99".to_vec()];
+    let server = Endpoint::server(
+        quinn::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        "127.0.0.1:0".parse()?,
     )?;
+    let target = server.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Some(connecting) = server.accept().await {
+            let _ = connecting.await;
+        }
+    });
 
+    let certs = ClientCerts {
+        server_cert: cert_der.clone(),
+        client_cert: cert_der,
+        client_key: key,
+    };
+    let endpoint = endpoint(&client_config(&certs, &Transport::default())?)?;
+    let connected = connect(&endpoint, &target, &Options::default(), &Arc::default()).await;
+    let message = match connected {
+        Ok(_) => bail!("connected without a version in common"),
+        Err(e) => format!("{e:#}"),
+    };
+    assert!(
+        message.contains("speaks no protocol version we do (qpipe/2, qpipe/1)"),
+        "{message}"
+    );
     Ok(())
 }
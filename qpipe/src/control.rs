@@ -0,0 +1,287 @@
+// A local (unix socket) control channel for a running client, using the same framing as the
+// tunnel. Each request is a single frame, each response is zero or more 'line' frames,
+// followed by either 'fini' (success) or 'errm' (failure).
+
+// 'fadd' - add a forward
+// source_len: u8
+// source: [u8; source_len] e.g. "localhost:2222"
+// target_len: u8
+// target: [u8; target_len] e.g. "example.com:22"
+
+// 'frem' - remove a forward
+// source_len: u8
+// source: [u8; source_len]
+
+// 'flst' - list forwards
+
+// 'stat' - connection and stream status
+
+// 'line' - one line of human-readable response
+// [all utf-8 bytes]
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::client::Client;
+use crate::frame::{HeaderHeader, Version};
+use crate::wire;
+
+// the socket is local, but the cli and the daemon could still be from different releases
+const VERSION: Version = Version::V1;
+
+#[derive(Debug, Clone)]
+pub enum Request {
+    AddForward { source: String, target: String },
+    RemoveForward { source: String },
+    ListForwards,
+    Status,
+}
+
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            bail!("{path:?} exists and isn't a socket, refusing to replace it")
+        }
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => bail!("another daemon is already listening on {path:?}"),
+            // a stale socket from a previous run would otherwise make the bind fail
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)
+                .with_context(|| anyhow!("removing stale control socket {path:?}"))?,
+            Err(e) => Err(e).with_context(|| anyhow!("checking control socket {path:?}"))?,
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => Err(e).with_context(|| anyhow!("checking control socket {path:?}"))?,
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| anyhow!("binding control socket {path:?}"))?;
+    // the umask picked the mode, and anyone who can connect can add forwards
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .with_context(|| anyhow!("restricting control socket {path:?}"))?;
+    Ok(listener)
+}
+
+pub async fn serve(listener: UnixListener, client: Arc<Client>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("control socket failed: {:?}", e);
+                return;
+            }
+        };
+        let client = Arc::clone(&client);
+        tokio::spawn(async move {
+            if let Err(e) = handle_control(stream, &client).await {
+                error!("control request failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_control(mut stream: UnixStream, client: &Arc<Client>) -> Result<()> {
+    let (hh, buf) = wire::read_frame(&mut stream, VERSION).await?;
+    let request = parse_request(&hh, &buf)?;
+    info!("control request: {:?}", request);
+
+    match execute(request, client).await {
+        Ok(lines) => {
+            for line in lines {
+                write_line(&mut stream, &line).await?;
+            }
+            HeaderHeader::finished()
+                .write_all(&mut stream, VERSION)
+                .await?;
+        }
+        Err(e) => wire::write_error(&mut stream, VERSION, 1, &format!("{e:#}")).await?,
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn execute(request: Request, client: &Arc<Client>) -> Result<Vec<String>> {
+    Ok(match request {
+        Request::AddForward { source, target } => client
+            .add_forward(&source, &target)
+            .await?
+            .into_iter()
+            .map(|source| format!("added {source} -> {target}"))
+            .collect(),
+        Request::RemoveForward { source } => client
+            .remove_forward(&source)
+            .await?
+            .into_iter()
+            .map(|source| format!("removed {source}"))
+            .collect(),
+        Request::ListForwards => client
+            .forwards()
+            .into_iter()
+            .map(|(source, target)| format!("{source} -> {target}"))
+            .collect(),
+        Request::Status => {
+            let connections = client.connections();
+            let streams = client.streams().snapshot();
+            let to = match connections.first() {
+                Some((conn, _)) => {
+                    format!(
+                        "connected to {}, rtt {:?}",
+                        conn.remote_address(),
+                        conn.rtt()
+                    )
+                }
+                // hung up, until it's needed
+                None => format!("not connected to {}", client.target()),
+            };
+            let mut lines = vec![format!(
+                "{to}, {} forwards, {} streams",
+                client.forwards().len(),
+                streams.len(),
+            )];
+            if connections.len() > 1 {
+                for (index, (conn, streams)) in connections.iter().enumerate() {
+                    let state = match conn.close_reason() {
+                        None => format!("rtt {:?}", conn.rtt()),
+                        Some(_) => "reconnecting".to_string(),
+                    };
+                    lines.push(format!("connection {index}: {state}, {streams} streams"));
+                }
+            }
+            let now = SystemTime::now();
+            for stream in streams {
+                lines.push(format!(
+                    "#{} {} -> {}: {} bytes sent, {} bytes received, {}s",
+                    stream.id,
+                    stream.peer,
+                    stream.address_port,
+                    stream.read.load(Ordering::Relaxed),
+                    stream.written.load(Ordering::Relaxed),
+                    now.duration_since(stream.started)
+                        .unwrap_or_default()
+                        .as_secs(),
+                ));
+            }
+            lines
+        }
+    })
+}
+
+/// send a request to a running client, returning the lines of its response
+pub async fn request(path: &Path, request: &Request) -> Result<Vec<String>> {
+    let mut stream = UnixStream::connect(path)
+        .await
+        .with_context(|| anyhow!("connecting to control socket {path:?}; is a daemon running?"))?;
+    write_request(&mut stream, request).await?;
+
+    let mut lines = Vec::new();
+    loop {
+        let (hh, buf) = wire::read_frame(&mut stream, VERSION).await?;
+        match &hh.four_cc {
+            b"line" => lines.push(String::from_utf8(buf)?),
+            b"fini" => return Ok(lines),
+            b"errm" => {
+                let (code, message) = wire::parse_error(&buf)?;
+                bail!("daemon refused request ({code}): {message}");
+            }
+            _ => bail!("unexpected control response: {:?}", hh),
+        }
+    }
+}
+
+async fn write_request(mut writer: impl AsyncWriteExt + Unpin, request: &Request) -> Result<()> {
+    let (four_cc, strings) = match request {
+        Request::AddForward { source, target } => (*b"fadd", vec![source, target]),
+        Request::RemoveForward { source } => (*b"frem", vec![source]),
+        Request::ListForwards => (*b"flst", vec![]),
+        Request::Status => (*b"stat", vec![]),
+    };
+    let buf = wire::encode_strings(&strings)?;
+    HeaderHeader {
+        four_cc,
+        data_len: buf.len() as u64,
+    }
+    .write_all(&mut writer, VERSION)
+    .await?;
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+fn parse_request(hh: &HeaderHeader, buf: &[u8]) -> Result<Request> {
+    let mut strings = wire::parse_strings(buf)?.into_iter();
+    let mut next = || strings.next().ok_or_else(|| anyhow!("missing argument"));
+
+    Ok(match &hh.four_cc {
+        b"fadd" => Request::AddForward {
+            source: next()?,
+            target: next()?,
+        },
+        b"frem" => Request::RemoveForward { source: next()? },
+        b"flst" => Request::ListForwards,
+        b"stat" => Request::Status,
+        _ => bail!("unsupported control request: {:?}", hh),
+    })
+}
+
+async fn write_line(mut writer: impl AsyncWriteExt + Unpin, line: &str) -> Result<()> {
+    HeaderHeader {
+        four_cc: *b"line",
+        data_len: line.len() as u64,
+    }
+    .write_all(&mut writer, VERSION)
+    .await?;
+    writer.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_round_trip() -> Result<()> {
+    let mut buf = Vec::new();
+    write_request(
+        &mut buf,
+        &Request::AddForward {
+            source: "localhost:2222".to_string(),
+            target: "example.com:22".to_string(),
+        },
+    )
+    .await?;
+    let (hh, body) = wire::read_frame(io::Cursor::new(buf.as_slice()), VERSION).await?;
+    match parse_request(&hh, &body)? {
+        Request::AddForward { source, target } => {
+            assert_eq!("localhost:2222", source);
+            assert_eq!("example.com:22", target);
+        }
+        other => panic!("unexpected request: {other:?}"),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bind() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    let file = dir.path().join("not-a-socket");
+    fs::write(&file, b"precious")?;
+    assert!(bind(&file).is_err());
+    assert_eq!(b"precious", fs::read(&file)?.as_slice());
+
+    let path = dir.path().join("qpiped.sock");
+    let listener = bind(&path)?;
+    assert_eq!(0o600, fs::metadata(&path)?.permissions().mode() & 0o777);
+    let err = bind(&path).unwrap_err();
+    assert!(err.to_string().contains("already listening"), "{err:?}");
+
+    // left behind, as if we'd crashed
+    drop(listener);
+    assert!(path.exists());
+    let _listener = bind(&path)?;
+    UnixStream::connect(&path).await?;
+    Ok(())
+}
@@ -0,0 +1,114 @@
+// Any FOURCC starting with 'xt' is an extension frame. They can appear wherever a peer is
+// reading frames: while a stream is being established, between 'data' frames, and in a
+// package. Extensions which have a handler registered are passed to it, and everything
+// else is skipped, so older peers don't fail on frames they don't know about.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{ensure, Result};
+use log::debug;
+
+use crate::frame::FourCc;
+
+pub fn is_extension(four_cc: &FourCc) -> bool {
+    four_cc.starts_with(b"xt")
+}
+
+/// something interested in one kind of extension frame
+pub trait Extension: Send + Sync {
+    /// returning an error fails whatever was being read; the stream, or the package
+    fn handle(&self, frame: &Frame<'_>) -> Result<()>;
+}
+
+impl<F> Extension for F
+where
+    F: Fn(&Frame<'_>) -> Result<()> + Send + Sync,
+{
+    fn handle(&self, frame: &Frame<'_>) -> Result<()> {
+        self(frame)
+    }
+}
+
+pub struct Frame<'a> {
+    pub four_cc: FourCc,
+    pub data: &'a [u8],
+    pub seen: Seen<'a>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Seen<'a> {
+    /// on a stream, sent by the peer with this certificate fingerprint
+    Stream {
+        identity: &'a str,
+        peer: SocketAddr,
+    },
+    Package,
+}
+
+/// handlers, by FOURCC
+#[derive(Clone, Default)]
+pub struct Extensions {
+    handlers: HashMap<FourCc, Arc<dyn Extension>>,
+}
+
+impl Extensions {
+    pub fn register(&mut self, four_cc: FourCc, handler: impl Extension + 'static) -> Result<()> {
+        ensure!(
+            is_extension(&four_cc),
+            "extension frames must start with 'xt', not {:?}",
+            String::from_utf8_lossy(&four_cc)
+        );
+        self.handlers.insert(four_cc, Arc::new(handler));
+        Ok(())
+    }
+
+    pub fn dispatch<'a>(&'a self, seen: Seen<'a>) -> Dispatch<'a> {
+        Dispatch {
+            extensions: self,
+            seen,
+        }
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.handlers
+                    .keys()
+                    .map(|four_cc| String::from_utf8_lossy(four_cc)),
+            )
+            .finish()
+    }
+}
+
+/// the extensions, and where the frames they're about to be handed came from
+#[derive(Copy, Clone)]
+pub struct Dispatch<'a> {
+    extensions: &'a Extensions,
+    seen: Seen<'a>,
+}
+
+impl Dispatch<'_> {
+    pub fn handle(&self, four_cc: FourCc, data: &[u8]) -> Result<()> {
+        match self.extensions.handlers.get(&four_cc) {
+            Some(handler) => handler.handle(&Frame {
+                four_cc,
+                data,
+                seen: self.seen,
+            }),
+            None => {
+                debug!(
+                    "skipping unknown extension frame {:?} ({} bytes), {:?}",
+                    String::from_utf8_lossy(&four_cc),
+                    data.len(),
+                    self.seen
+                );
+                Ok(())
+            }
+        }
+    }
+}
@@ -3,6 +3,15 @@
 // FOURCC: [u8; 4]
 // DATA_LENGTH: u16 (excludes header)
 
+// The version is picked with ALPN during the QUIC handshake: `qpipe/1` or `qpipe/2`. The
+// server picks its most preferred version which the client also offers. `hq-29` is an alias
+// for version 1, as that's what releases before version negotiation offered.
+
+// Version 2 only changes DATA_LENGTH, to a QUIC-style variable length
+// integer (RFC 9000, section 16): big endian, with the top two bits of the first byte
+// giving the total length: 0b00 -> 1 byte, 0b01 -> 2, 0b10 -> 4, 0b11 -> 8. That's up
+// to 2^62 - 1, and a byte shorter than v1 for small frames.
+
 // e.g. "errm\0\7\0\0\0\1\2hi"
 //           ^^^^ data length
 //               ^^^^^^^^ error code
@@ -13,11 +22,22 @@
 // tcp/udp: 't' | 'u'
 // address_port_len: u8
 // address_port: [u8; address_port_len] e.g. "example.com:80"
+// flags: u8, see FLAG_*; absent means 0
+// priority: i32, for the server's side of the stream, as `quinn::SendStream::set_priority`;
+//   absent means 0
 // [unspecified]
 
 // 'okay'
+// flags: u8, those from the 'con1' the server agreed to; absent means 0
 // [unspecified]
 
+// With FLAG_RAW agreed, the 'okay' is the last frame on the stream: from then on it carries
+// the user's bytes as-is in both directions. No more writes from one side is the QUIC stream
+// being finished.
+
+// In either mode, a failure is the stream being reset (and stopped), with a RESET_* code.
+// RESET_TCP asks the other side to reset its plain connection, as ours was.
+
 // 'errm' - error with message
 // code: u32,
 // message_len: u8
@@ -25,7 +45,7 @@
 // [unspecified]
 
 // 'data' (len=0 is illegal?)
-// [all user bytes]
+// [all user bytes]; no more than 8096 of them in v1, as older releases refuse longer frames
 
 // 'fini' - no more writes from my side
 // [unspecified]
@@ -38,58 +58,215 @@
 // token: u64
 // [unspecified]
 
-// 'scrt' - server cert
-// cert bytes as der
+// 'scrt', 'ccrt', 'ckey', ... - only in packages; see package.rs
 
-// 'ccrt' - client cert
-// cert bytes as der
-
-// 'ckey' - client key
-// key bytes as der
+// 'xt??' (anything starting with 'xt') - an extension, skipped unless something is
+// registered to handle it; see extension.rs
+// [unspecified]
 
-// 'xt??' (anything starting with 'xt')
+// 'gway' - server is shutting down; sent on a server-initiated uni stream.
+// Streams already established will be served until the drain timeout, new
+// streams should be opened on a new connection.
 // [unspecified]
 
 use std::fmt;
-
-use anyhow::{bail, Result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
+use std::io;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Error, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::warn;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::try_join;
+
+use crate::extension::{is_extension, Dispatch};
+use crate::shaping::Shaping;
 use crate::wire;
 
 pub type FourCc = [u8; 4];
 
+// application close codes, for the whole connection
+pub const CLOSE_DONE: u32 = 0;
+pub const CLOSE_SHUTDOWN: u32 = 1;
+pub const CLOSE_LIMITED: u32 = 2;
+
+// 'errm' codes
+pub const ERR_UNRECOGNISED_FRAME: u32 = 1;
+pub const ERR_UNSUPPORTED_PROTOCOL: u32 = 2;
+pub const ERR_RESOLUTION: u32 = 3;
+pub const ERR_CONNECT: u32 = 4;
+pub const ERR_LIMITED: u32 = 5;
+
+// 'con1' / 'okay' flags
+pub const FLAG_RAW: u8 = 1;
+/// everything we know how to agree to
+pub const SUPPORTED_FLAGS: u8 = FLAG_RAW;
+
+// stream reset / stop codes
+pub const RESET_ABORTED: u32 = 1;
+/// the plain connection was reset; reset yours too
+pub const RESET_TCP: u32 = 2;
+
+/// which header format a stream, or package, uses
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Version {
+    /// u16 lengths
+    V1,
+    /// varint lengths
+    V2,
+}
+
+impl Version {
+    /// every version we speak, most preferred first
+    pub const ALL: [Version; 2] = [Version::V2, Version::V1];
+
+    /// the ALPN protocol identifier which selects this version
+    pub fn alpn(self) -> &'static [u8] {
+        match self {
+            Version::V1 => b"qpipe/1",
+            Version::V2 => b"qpipe/2",
+        }
+    }
+
+    pub fn from_alpn(protocol: &[u8]) -> Option<Self> {
+        if protocol == LEGACY_ALPN {
+            return Some(Version::V1);
+        }
+        Version::ALL
+            .into_iter()
+            .find(|version| version.alpn() == protocol)
+    }
+
+    /// what was agreed for `conn`; QUIC insists on ALPN, so there's always something
+    pub fn negotiated(conn: &quinn::Connection) -> Self {
+        conn.handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|data| data.protocol)
+            .and_then(|protocol| Version::from_alpn(&protocol))
+            .unwrap_or(Version::V1)
+    }
+
+    /// the largest DATA_LENGTH the header can carry
+    pub fn max_data_len(self) -> u64 {
+        match self {
+            Version::V1 => u64::from(u16::MAX),
+            Version::V2 => MAX_VARINT,
+        }
+    }
+
+    /// the most we put in one 'data' frame: v1 may be an older release, which refuses
+    /// frames longer than its 8096 byte buffer
+    pub fn max_frame_data(self) -> usize {
+        let max = match self {
+            Version::V1 => 8096,
+            Version::V2 => MAX_DATA_LEN,
+        };
+        usize::try_from(self.max_data_len()).map_or(max, |len| len.min(max))
+    }
+}
+
+const MAX_VARINT: u64 = (1 << 62) - 1;
+
+/// What releases before `qpipe/1` offered, which is really v1. It was borrowed from quinn's
+/// example, and claims to be HTTP/0.9 over draft 29 QUIC, so it's only offered last.
+pub const LEGACY_ALPN: &[u8] = b"hq-29";
+
+/// the ALPN protocols to offer, or accept, most preferred first
+pub fn alpn_protocols() -> Vec<Vec<u8>> {
+    Version::ALL
+        .into_iter()
+        .map(|version| version.alpn())
+        .chain([LEGACY_ALPN])
+        .map(|protocol| protocol.to_vec())
+        .collect()
+}
+
+/// did the handshake fail because there was no ALPN protocol both sides speak
+pub fn no_shared_version(e: &quinn::ConnectionError) -> bool {
+    // a TLS `no_application_protocol` alert, as a QUIC CRYPTO_ERROR; RFC 9001, section 8.1
+    const NO_APPLICATION_PROTOCOL: u64 = 0x100 | 120;
+    let code = match e {
+        quinn::ConnectionError::ConnectionClosed(close) => close.error_code,
+        quinn::ConnectionError::TransportError(e) => e.code,
+        _ => return false,
+    };
+    u64::from(code) == NO_APPLICATION_PROTOCOL
+}
+
+/// for error messages: "qpipe/2, qpipe/1"
+pub fn describe_versions() -> String {
+    Version::ALL
+        .into_iter()
+        .map(|version| String::from_utf8_lossy(version.alpn()).to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// the longest a header can be, in any version
+pub const MAX_HEADER_LEN: usize = 4 + 8;
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct HeaderHeader {
     pub four_cc: FourCc,
-    pub data_len: u16,
+    pub data_len: u64,
 }
 
 impl HeaderHeader {
-    pub async fn from(mut reader: impl AsyncReadExt + Unpin) -> Result<Self> {
-        let mut buf = [0u8; 4 + 2];
-        reader.read_exact(&mut buf).await?;
-        Ok(HeaderHeader {
-            four_cc: [buf[0], buf[1], buf[2], buf[3]],
-            data_len: u16::from_le_bytes([buf[4], buf[5]]),
-        })
+    pub async fn from(mut reader: impl AsyncReadExt + Unpin, version: Version) -> Result<Self> {
+        let mut four_cc = [0u8; 4];
+        reader.read_exact(&mut four_cc).await?;
+        let data_len = match version {
+            Version::V1 => u64::from(reader.read_u16_le().await?),
+            Version::V2 => {
+                let first = reader.read_u8().await?;
+                let mut buf = [0u8; 8];
+                let len = 1 << (first >> 6);
+                buf[8 - len] = first & 0b0011_1111;
+                reader.read_exact(&mut buf[8 - len + 1..]).await?;
+                u64::from_be_bytes(buf)
+            }
+        };
+        Ok(HeaderHeader { four_cc, data_len })
     }
 
-    pub async fn write_all(&self, mut writer: impl AsyncWriteExt + Unpin) -> Result<()> {
-        let data_len = self.data_len.to_le_bytes();
-        writer
-            .write_all(&[
-                self.four_cc[0],
-                self.four_cc[1],
-                self.four_cc[2],
-                self.four_cc[3],
-                data_len[0],
-                data_len[1],
-            ])
-            .await?;
+    pub async fn write_all(
+        &self,
+        mut writer: impl AsyncWriteExt + Unpin,
+        version: Version,
+    ) -> Result<()> {
+        writer.write_all(&self.to_bytes(version)?).await?;
         Ok(())
     }
 
+    /// fails if `data_len` doesn't fit in this version's header
+    pub fn to_bytes(&self, version: Version) -> Result<Vec<u8>> {
+        ensure!(
+            self.data_len <= version.max_data_len(),
+            "{:?} is too long for a {:?} header",
+            self,
+            version
+        );
+        let mut buf = Vec::with_capacity(MAX_HEADER_LEN);
+        buf.extend_from_slice(&self.four_cc);
+        match version {
+            Version::V1 => buf.extend_from_slice(&(self.data_len as u16).to_le_bytes()),
+            Version::V2 => {
+                let (len, tag) = match self.data_len {
+                    0..=0x3f => (1, 0b00),
+                    0x40..=0x3fff => (2, 0b01),
+                    0x4000..=0x3fff_ffff => (4, 0b10),
+                    _ => (8, 0b11),
+                };
+                let bytes = self.data_len.to_be_bytes();
+                let start = buf.len();
+                buf.extend_from_slice(&bytes[8 - len..]);
+                buf[start] |= tag << 6;
+            }
+        }
+        Ok(buf)
+    }
+
     pub fn empty(four_cc: FourCc) -> Self {
         HeaderHeader {
             four_cc,
@@ -120,35 +297,77 @@ impl HeaderHeader {
     pub fn error(string_length: u8) -> Self {
         HeaderHeader {
             four_cc: *b"errm",
-            data_len: 4 + 1 + u16::from(string_length),
+            data_len: 4 + 1 + u64::from(string_length),
         }
     }
 
     pub fn data(len: usize) -> Self {
         HeaderHeader {
             four_cc: *b"data",
-            data_len: u16::try_from(len).expect("long data len unsupported"),
+            data_len: len as u64,
         }
     }
 }
 
 #[tokio::test]
 async fn test_header_header() {
-    use std::io;
-
     let mut buf = Vec::new();
     let start = HeaderHeader {
         four_cc: *b"abcd",
         data_len: 259,
     };
-    start.write_all(&mut buf).await.expect("infalliable / test");
-    let end = HeaderHeader::from(io::Cursor::new(buf.as_slice()))
+    start
+        .write_all(&mut buf, Version::V1)
+        .await
+        .expect("infalliable / test");
+    let end = HeaderHeader::from(io::Cursor::new(buf.as_slice()), Version::V1)
         .await
         .expect("test");
 
     assert_eq!(start, end);
 }
 
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_header_round_trip(four_cc: FourCc, data_len in header_lengths()) {
+        let start = HeaderHeader { four_cc, data_len };
+        for version in [Version::V1, Version::V2] {
+            let buf = match start.to_bytes(version) {
+                Ok(buf) => buf,
+                Err(_) => {
+                    proptest::prop_assert!(data_len > version.max_data_len());
+                    continue;
+                }
+            };
+            proptest::prop_assert!(buf.len() <= MAX_HEADER_LEN);
+            let end = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .expect("runtime")
+                .block_on(HeaderHeader::from(buf.as_slice(), version))
+                .expect("reading what we wrote");
+            proptest::prop_assert_eq!(start, end);
+        }
+    }
+}
+
+/// either side of every size boundary, in both versions, or anything at all
+#[cfg(test)]
+fn header_lengths() -> impl proptest::strategy::Strategy<Value = u64> {
+    let boundaries = [6, 14, 16, 30, 62]
+        .into_iter()
+        .flat_map(|bits| {
+            let boundary = 1u64 << bits;
+            [boundary - 2, boundary - 1, boundary, boundary + 1]
+        })
+        .chain([0, 1, u64::MAX])
+        .collect::<Vec<_>>();
+    proptest::prop_oneof![
+        proptest::sample::select(boundaries),
+        proptest::num::u64::ANY
+    ]
+}
+
 impl fmt::Debug for HeaderHeader {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -160,56 +379,336 @@ impl fmt::Debug for HeaderHeader {
     }
 }
 
+// the most we read from a plain connection at once, so the most a single 'data' frame we
+// send carries, in v2
+pub const MAX_DATA_LEN: usize = 256 * 1024;
+
 pub async fn copy_framing(
-    mut from_plain: impl AsyncReadExt + Unpin,
-    mut to_framed: impl AsyncWriteExt + Unpin,
+    mut from_plain: impl AsyncRead + Unpin,
+    to_framed: &mut quinn::SendStream,
+    version: Version,
+    shaping: &Shaping,
 ) -> Result<()> {
-    // small buffer here; we need the whole frame to arrive at the other end before processing it,
-    // and a smaller packet is more likely to arrive. We aren't flushing (maybe we should be flushing),
-    // so the overhead of the small packet is very low; especially as (I assume) both read() and
-    // write() here are heavily buffered. Intentionally, but potentially naively, ignoring the
-    // underlying framing here; we've told it we're a stream transport, and we're building on top
-    // of that stream, regardless of how it is implemented.
-    let mut buf = [0u8; 256];
+    // Each read becomes one 'data' frame, up to `max_frame_data`; a read only returns what's
+    // already available, so interactive traffic still gets small frames promptly, and bulk
+    // traffic gets big frames. The header and the data are handed to quinn as separate
+    // chunks, which it keeps (without copying) until they're acknowledged; the allocation
+    // is reused once quinn is done with it, otherwise `reserve` finds us a new one.
+    let mut buf = BytesMut::with_capacity(version.max_frame_data());
+    let limit = shaping.quantum(version.max_frame_data());
 
     loop {
-        let found = from_plain.read(&mut buf).await?;
-        let buf = &buf[..found];
-        if buf.is_empty() {
+        buf.reserve(limit);
+        let found = from_plain.read_buf(&mut (&mut buf).limit(limit)).await?;
+        if found == 0 {
             break;
         }
+        shaping.spend(found).await;
 
-        wire::write_data(&mut to_framed, buf).await?;
+        let header = Bytes::from(HeaderHeader::data(found).to_bytes(version)?);
+        to_framed
+            .write_all_chunks(&mut [header, buf.split().freeze()])
+            .await?;
     }
 
     Ok(())
 }
 
 pub async fn copy_unframing(
-    mut from_framed: impl AsyncReadExt + Unpin,
-    mut to_plain: impl AsyncWriteExt + Unpin,
+    from_framed: &mut quinn::RecvStream,
+    mut to_plain: impl AsyncWrite + Unpin,
+    version: Version,
+    dispatch: Dispatch<'_>,
+    shaping: &Shaping,
 ) -> Result<()> {
-    // arbitrary limit on the server; didn't fancy having a 65kB buffer here per connection,
-    // seems excessive. Could have an auto-resizing Buf?
-    let mut buf = [0u8; 8096];
-
+    // no buffer of our own; quinn hands us the (ordered) chunks it has already received
     loop {
-        let hh = HeaderHeader::from(&mut from_framed).await?;
+        let hh = HeaderHeader::from(&mut *from_framed, version).await?;
         match &hh.four_cc {
             b"data" => (),
             b"fini" => break,
+            four_cc if is_extension(four_cc) => {
+                let buf = wire::read_body(&mut *from_framed, &hh).await?;
+                dispatch.handle(hh.four_cc, &buf)?;
+                continue;
+            }
             _ => bail!("unsupported frame on established connection: {:?}", hh),
         };
 
-        if usize::from(hh.data_len) > buf.len() {
-            bail!("overlong data packet: {}", hh.data_len)
+        let mut remaining = hh.data_len;
+        while remaining > 0 {
+            let wanted = shaping.quantum(usize::try_from(remaining).unwrap_or(usize::MAX));
+            let chunk = from_framed
+                .read_chunk(wanted, true)
+                .await?
+                .ok_or_else(|| anyhow!("stream finished inside a data frame"))?;
+            remaining -= chunk.bytes.len() as u64;
+            shaping.spend(chunk.bytes.len()).await;
+            to_plain.write_all(&chunk.bytes).await?;
         }
+    }
 
-        let buf = &mut buf[..usize::from(hh.data_len)];
+    Ok(())
+}
+
+/// move bytes both ways between a plain connection and an established stream, until both
+/// directions are finished; half-closes are passed across as they happen
+#[allow(clippy::too_many_arguments)]
+pub async fn splice(
+    version: Version,
+    raw: bool,
+    dispatch: Dispatch<'_>,
+    shaping: &Shaping,
+    mut plain_from: impl AsyncRead + Unpin,
+    mut plain_to: impl AsyncWrite + Unpin,
+    framed_to: &mut quinn::SendStream,
+    framed_from: &mut quinn::RecvStream,
+) -> Result<()> {
+    framed_to.set_priority(shaping.priority)?;
+    let res = try_join!(
+        async {
+            if raw {
+                copy_to_raw(&mut plain_from, framed_to, shaping).await?;
+            } else {
+                copy_framing(&mut plain_from, framed_to, version, shaping).await?;
+                HeaderHeader::finished()
+                    .write_all(&mut *framed_to, version)
+                    .await?;
+            }
+            framed_to.finish().await?;
+            Ok::<_, Error>(())
+        },
+        async {
+            if raw {
+                copy_from_raw(framed_from, &mut plain_to, shaping).await?;
+            } else {
+                copy_unframing(framed_from, &mut plain_to, version, dispatch, shaping).await?;
+            }
+            plain_to.shutdown().await?;
+            Ok(())
+        }
+    );
+
+    if let Err(e) = &res {
+        // pass on the peer's code, so a reset travels the whole way
+        let code = match reset_code(e) {
+            Some(Some(code)) => code,
+            Some(None) => RESET_TCP,
+            None => RESET_ABORTED,
+        };
+        // either may already be finished or reset, which is fine
+        let _ = framed_to.reset(code.into());
+        let _ = framed_from.stop(code.into());
+    }
+
+    res.map(|_| ())
+}
+
+/// did the peer ask us to reset our plain connection
+pub fn wants_tcp_reset(res: &Result<()>) -> bool {
+    match res {
+        Ok(()) => false,
+        Err(e) => reset_code(e) == Some(Some(RESET_TCP)),
+    }
+}
+
+/// close with a RST instead of a FIN, discarding anything unsent
+pub fn reset_tcp(plain_from: OwnedReadHalf, plain_to: OwnedWriteHalf) {
+    match plain_from.reunite(plain_to) {
+        // dropping without a shutdown() closes, and a zero linger makes that close a reset
+        Ok(stream) => {
+            if let Err(e) = stream.set_linger(Some(Duration::ZERO)) {
+                warn!("couldn't reset plain connection: {e:?}");
+            }
+        }
+        Err(e) => warn!("couldn't reset plain connection: {e:?}"),
+    }
+}
+
+/// was this error the stream being reset, and with which code, if it was the peer
+pub fn reset_code(e: &Error) -> Option<Option<u32>> {
+    fn quic_reset(e: &(dyn std::error::Error + 'static)) -> Option<u32> {
+        let code = match e.downcast_ref() {
+            Some(quinn::ReadError::Reset(code)) => code,
+            _ => match e.downcast_ref() {
+                Some(quinn::WriteError::Stopped(code)) => code,
+                _ => return None,
+            },
+        };
+        u32::try_from(code.into_inner()).ok()
+    }
+
+    for cause in e.chain() {
+        if let Some(code) = quic_reset(cause) {
+            return Some(Some(code));
+        }
+        if let Some(io) = cause.downcast_ref::<io::Error>() {
+            match io.get_ref() {
+                Some(inner) => {
+                    if let Some(code) = quic_reset(inner) {
+                        return Some(Some(code));
+                    }
+                }
+                // a plain tcp reset, or a write after one
+                None if matches!(
+                    io.kind(),
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+                ) =>
+                {
+                    return Some(None)
+                }
+                None => (),
+            }
+        }
+    }
+    None
+}
+
+/// raw mode: our plain bytes, as-is
+async fn copy_to_raw(
+    mut from_plain: impl AsyncRead + Unpin,
+    to_framed: &mut quinn::SendStream,
+    shaping: &Shaping,
+) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(MAX_DATA_LEN);
+    let limit = shaping.quantum(MAX_DATA_LEN);
 
-        from_framed.read_exact(buf).await?;
-        to_plain.write_all(buf).await?;
+    loop {
+        buf.reserve(limit);
+        let found = from_plain.read_buf(&mut (&mut buf).limit(limit)).await?;
+        if found == 0 {
+            break;
+        }
+        shaping.spend(found).await;
+        to_framed.write_chunk(buf.split().freeze()).await?;
     }
 
     Ok(())
 }
+
+/// raw mode: the stream's bytes, as-is, until it's finished
+async fn copy_from_raw(
+    from_framed: &mut quinn::RecvStream,
+    mut to_plain: impl AsyncWrite + Unpin,
+    shaping: &Shaping,
+) -> Result<()> {
+    let wanted = shaping.quantum(usize::MAX);
+    while let Some(chunk) = from_framed.read_chunk(wanted, true).await? {
+        shaping.spend(chunk.bytes.len()).await;
+        to_plain.write_all(&chunk.bytes).await?;
+    }
+
+    Ok(())
+}
+
+/// `sent`, through `copy_framing` over a loopback connection: how long each frame was, and
+/// everything they carried
+#[cfg(test)]
+async fn framed_lengths(sent: &[u8], version: Version) -> Result<(Vec<u64>, Vec<u8>)> {
+    let (ours, theirs) = loopback().await?;
+    let mut to_framed = ours.open_uni().await?;
+    let write = async {
+        copy_framing(sent, &mut to_framed, version, &Shaping::default()).await?;
+        HeaderHeader::finished()
+            .write_all(&mut to_framed, version)
+            .await?;
+        to_framed.finish().await?;
+        Ok::<_, Error>(())
+    };
+    let read = async {
+        let mut from_framed = theirs.accept_uni().await?;
+        let (mut lengths, mut received) = (Vec::new(), Vec::new());
+        loop {
+            let hh = HeaderHeader::from(&mut from_framed, version).await?;
+            if &hh.four_cc == b"fini" {
+                return Ok::<_, Error>((lengths, received));
+            }
+            let start = received.len();
+            received.resize(start + usize::try_from(hh.data_len)?, 0);
+            from_framed.read_exact(&mut received[start..]).await?;
+            lengths.push(hh.data_len);
+        }
+    };
+    Ok(try_join!(write, read)?.1)
+}
+
+/// a connection to ourselves, from both ends
+#[cfg(test)]
+async fn loopback() -> Result<(quinn::Connection, quinn::Connection)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let cert_der = rustls::Certificate(cert.serialize_der()?);
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let server_config = quinn::ServerConfig::with_single_cert(vec![cert_der.clone()], key)?;
+    let server = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse()?)?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&cert_der)?;
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse()?)?;
+    client.set_default_client_config(quinn::ClientConfig::with_root_certificates(roots));
+
+    let connecting = client.connect(server.local_addr()?, "localhost")?;
+    let (ours, theirs) = try_join!(async { Ok::<_, Error>(connecting.await?) }, async {
+        let incoming = server
+            .accept()
+            .await
+            .ok_or_else(|| anyhow!("endpoint closed"))?;
+        Ok(incoming.await?)
+    })?;
+    Ok((ours, theirs))
+}
+
+#[tokio::test]
+async fn test_v1_frames_fit_old_buffers() -> Result<()> {
+    let sent = (0..1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let (lengths, received) = framed_lengths(&sent, Version::V1).await?;
+    assert!(sent == received, "framed something else");
+    assert!(lengths.iter().all(|&len| len <= 8096), "{lengths:?}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_v2_frames_over_64k() -> Result<()> {
+    let sent = (0..1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let (lengths, received) = framed_lengths(&sent, Version::V2).await?;
+    assert!(sent == received, "framed something else");
+    assert!(
+        lengths.iter().any(|&len| len > u64::from(u16::MAX)),
+        "{lengths:?}"
+    );
+
+    // and a single such frame unframes
+    let (ours, theirs) = loopback().await?;
+    let mut to_framed = ours.open_uni().await?;
+    let write = async {
+        HeaderHeader::data(sent.len())
+            .write_all(&mut to_framed, Version::V2)
+            .await?;
+        to_framed.write_all(&sent).await?;
+        HeaderHeader::finished()
+            .write_all(&mut to_framed, Version::V2)
+            .await?;
+        to_framed.finish().await?;
+        Ok::<_, Error>(())
+    };
+    let read = async {
+        let mut from_framed = theirs.accept_uni().await?;
+        let mut received = Vec::new();
+        let extensions = crate::extension::Extensions::default();
+        copy_unframing(
+            &mut from_framed,
+            &mut received,
+            Version::V2,
+            extensions.dispatch(crate::extension::Seen::Package),
+            &Shaping::default(),
+        )
+        .await?;
+        Ok::<_, Error>(received)
+    };
+    let ((), received) = try_join!(write, read)?;
+    assert!(sent == received, "unframed something else");
+    Ok(())
+}
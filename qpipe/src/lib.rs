@@ -1,6 +1,23 @@
+pub mod access_log;
 pub mod certs;
 pub mod client;
+#[cfg(unix)]
+pub mod control;
+pub mod extension;
 pub mod frame;
+pub mod limits;
+pub mod metrics;
+#[cfg(target_os = "linux")]
+mod netwatch;
 pub mod package;
+pub mod profiles;
+pub mod seal;
 pub mod server;
+pub mod sessions;
+pub mod shaping;
+pub mod state;
+pub mod streams;
+#[cfg(unix)]
+pub mod systemd;
+pub mod transport;
 mod wire;
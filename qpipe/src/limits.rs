@@ -0,0 +1,191 @@
+// Per-identity quotas on the server: how many connections and streams a client certificate
+// may have open at once, how quickly it may open new streams, and how much it may move,
+// across all of them. Its bandwidth is shaped like any other limit; see shaping.rs.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::shaping::{Shaper, TokenBucket};
+
+/// what each client identity is allowed; `None` is unlimited
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// open QUIC connections
+    pub connections: Option<usize>,
+    /// open streams, across all its connections
+    pub streams: Option<usize>,
+    /// new streams per second, in bursts of up to a second's worth
+    pub stream_rate: Option<f64>,
+    /// bytes per second, in both directions together, across all its streams
+    pub bandwidth: Option<u64>,
+}
+
+/// which limit a client ran into, as reported in metrics
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Exceeded {
+    Connections,
+    Streams,
+    StreamRate,
+}
+
+impl Exceeded {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Exceeded::Connections => "connections",
+            Exceeded::Streams => "streams",
+            Exceeded::StreamRate => "stream_rate",
+        }
+    }
+
+    /// for the client, in the `errm` or close reason
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Exceeded::Connections => "too many connections",
+            Exceeded::Streams => "too many streams",
+            Exceeded::StreamRate => "opening streams too quickly",
+        }
+    }
+}
+
+/// usage of every identity with anything open, checked against the `Limits`
+#[derive(Default)]
+pub struct Quotas {
+    limits: Limits,
+    usage: Mutex<HashMap<String, Arc<Usage>>>,
+}
+
+struct Usage {
+    connections: AtomicUsize,
+    streams: AtomicUsize,
+    stream_rate: Option<TokenBucket>,
+    bandwidth: Option<Arc<Shaper>>,
+}
+
+/// counts against the identity's connections until dropped
+pub struct ConnectionPermit {
+    usage: Arc<Usage>,
+}
+
+/// counts against the identity's streams until dropped
+pub struct StreamPermit {
+    usage: Arc<Usage>,
+}
+
+impl Quotas {
+    pub fn new(limits: Limits) -> Self {
+        Quotas {
+            limits,
+            usage: Mutex::default(),
+        }
+    }
+
+    pub fn connection(&self, identity: &str) -> Result<ConnectionPermit, Exceeded> {
+        let usage = self.usage(identity);
+        take(
+            &usage.connections,
+            self.limits.connections,
+            Exceeded::Connections,
+        )?;
+        Ok(ConnectionPermit { usage })
+    }
+
+    pub fn stream(&self, identity: &str) -> Result<StreamPermit, Exceeded> {
+        let usage = self.usage(identity);
+        if let Some(rate) = &usage.stream_rate {
+            if !rate.try_take(1.) {
+                return Err(Exceeded::StreamRate);
+            }
+        }
+        take(&usage.streams, self.limits.streams, Exceeded::Streams)?;
+        Ok(StreamPermit { usage })
+    }
+
+    fn usage(&self, identity: &str) -> Arc<Usage> {
+        let mut usage = self.usage.lock().expect("poisoned");
+        if let Some(found) = usage.get(identity) {
+            return Arc::clone(found);
+        }
+        // forget anyone who's gone away, and isn't still paying off their buckets
+        usage.retain(|_, usage| Arc::strong_count(usage) > 1 || !usage.is_idle());
+        let created = Arc::new(Usage {
+            connections: AtomicUsize::new(0),
+            streams: AtomicUsize::new(0),
+            stream_rate: self
+                .limits
+                .stream_rate
+                .map(|rate| TokenBucket::new(rate, rate.max(1.))),
+            bandwidth: self
+                .limits
+                .bandwidth
+                .map(|rate| Shaper::new("identity", rate)),
+        });
+        usage.insert(identity.to_string(), Arc::clone(&created));
+        created
+    }
+}
+
+/// count one more against `limit`, unless that's too many
+fn take(count: &AtomicUsize, limit: Option<usize>, exceeded: Exceeded) -> Result<(), Exceeded> {
+    count
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| match limit {
+            Some(limit) if count >= limit => None,
+            _ => Some(count + 1),
+        })
+        .map(|_| ())
+        .map_err(|_| exceeded)
+}
+
+impl Usage {
+    fn is_idle(&self) -> bool {
+        self.stream_rate.as_ref().is_none_or(TokenBucket::is_full)
+            && self
+                .bandwidth
+                .as_ref()
+                .is_none_or(|shaper| shaper.is_idle())
+    }
+}
+
+impl StreamPermit {
+    /// the identity's bandwidth allowance, if it has one
+    pub fn bandwidth(&self) -> Option<&Arc<Shaper>> {
+        self.usage.bandwidth.as_ref()
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.usage.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.usage.streams.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[test]
+fn test_quotas() {
+    let quotas = Quotas::new(Limits {
+        connections: Some(1),
+        streams: Some(2),
+        stream_rate: Some(3.),
+        ..Limits::default()
+    });
+    let conn = quotas.connection("alice").expect("first");
+    assert_eq!(
+        Some(Exceeded::Connections),
+        quotas.connection("alice").err()
+    );
+    assert!(quotas.connection("bob").is_ok());
+    drop(conn);
+    let _conn = quotas.connection("alice").expect("after the first closed");
+
+    let first = quotas.stream("alice").expect("first");
+    let _second = quotas.stream("alice").expect("second");
+    assert_eq!(Some(Exceeded::Streams), quotas.stream("alice").err());
+    drop(first);
+    // the refused stream still spent its token, so the burst of three is used up
+    assert_eq!(Some(Exceeded::StreamRate), quotas.stream("alice").err());
+}
@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use log::{error, info};
+use quinn::Connection;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::limits::Exceeded;
+use crate::streams::Streams;
+
+/// how long a scraper has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// counters and gauges for a client or server, in addition to what `Streams` already knows
+#[derive(Default)]
+pub struct Metrics {
+    // by stable_id, with the identity of the peer
+    connections: Mutex<HashMap<usize, (String, Connection)>>,
+    handshake_failures: AtomicU64,
+    rebinds: AtomicU64,
+    // by `errm` code
+    connect_failures: Mutex<BTreeMap<u32, u64>>,
+    limits_exceeded: Mutex<BTreeMap<Exceeded, u64>>,
+    // by shaper name
+    throttled: Mutex<BTreeMap<String, Duration>>,
+}
+
+impl Metrics {
+    /// track `conn` until it closes
+    pub fn connection_opened(self: &Arc<Self>, identity: String, conn: &Connection) {
+        let id = conn.stable_id();
+        self.connections
+            .lock()
+            .expect("poisoned")
+            .insert(id, (identity, conn.clone()));
+        let metrics = Arc::clone(self);
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            conn.closed().await;
+            metrics.connections.lock().expect("poisoned").remove(&id);
+        });
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// the client moved to a new socket, as the network changed
+    pub fn rebound(&self) {
+        self.rebinds.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_failed(&self, code: u32) {
+        *self
+            .connect_failures
+            .lock()
+            .expect("poisoned")
+            .entry(code)
+            .or_default() += 1;
+    }
+
+    pub fn limit_exceeded(&self, limit: Exceeded) {
+        *self
+            .limits_exceeded
+            .lock()
+            .expect("poisoned")
+            .entry(limit)
+            .or_default() += 1;
+    }
+
+    /// streams waited `waited` for a bandwidth limit
+    pub fn throttled(&self, shaper: &str, waited: Duration) {
+        let mut throttled = self.throttled.lock().expect("poisoned");
+        match throttled.get_mut(shaper) {
+            Some(total) => *total += waited,
+            None => {
+                throttled.insert(shaper.to_string(), waited);
+            }
+        }
+    }
+
+    /// the prometheus text exposition format
+    pub fn render(&self, streams: &Streams) -> String {
+        let mut out = Exposition::default();
+
+        let connections = self
+            .connections
+            .lock()
+            .expect("poisoned")
+            .values()
+            .map(|(identity, conn)| {
+                let path = conn.stats().path;
+                let path = PathSample {
+                    rtt: path.rtt,
+                    cwnd: path.cwnd,
+                    congestion_events: path.congestion_events,
+                    sent_packets: path.sent_packets,
+                    lost_packets: path.lost_packets,
+                };
+                (identity.to_string(), conn.remote_address(), path)
+            })
+            .collect::<Vec<_>>();
+
+        let mut by_identity = BTreeMap::<&str, u64>::new();
+        for (identity, _, _) in &connections {
+            *by_identity.entry(identity).or_default() += 1;
+        }
+        out.family("qpipe_connections", "gauge", "open QUIC connections");
+        for (identity, count) in by_identity {
+            out.sample("qpipe_connections", &[("identity", identity)], count);
+        }
+
+        out.family("qpipe_streams", "gauge", "open streams");
+        out.sample("qpipe_streams", &[], streams.len() as u64);
+
+        out.family(
+            "qpipe_stream_bytes_total",
+            "counter",
+            "bytes moved by streams, by target; the busiest, then the rest as \"other\"",
+        );
+        for (target, read, written) in streams.totals() {
+            let labels = |direction| [("target", target.as_str()), ("direction", direction)];
+            out.sample("qpipe_stream_bytes_total", &labels("sent"), read);
+            out.sample("qpipe_stream_bytes_total", &labels("received"), written);
+        }
+
+        out.family(
+            "qpipe_connect_failures_total",
+            "counter",
+            "streams refused, by error code",
+        );
+        for (code, count) in self.connect_failures.lock().expect("poisoned").iter() {
+            let code = code.to_string();
+            out.sample("qpipe_connect_failures_total", &[("code", &code)], *count);
+        }
+
+        out.family(
+            "qpipe_limits_exceeded_total",
+            "counter",
+            "connections and streams refused, by limit",
+        );
+        for (limit, count) in self.limits_exceeded.lock().expect("poisoned").iter() {
+            out.sample(
+                "qpipe_limits_exceeded_total",
+                &[("limit", limit.as_str())],
+                *count,
+            );
+        }
+
+        out.family(
+            "qpipe_throttled_seconds_total",
+            "counter",
+            "time streams spent waiting for bandwidth, by limit",
+        );
+        for (shaper, waited) in self.throttled.lock().expect("poisoned").iter() {
+            out.sample(
+                "qpipe_throttled_seconds_total",
+                &[("limit", shaper)],
+                waited.as_secs_f64(),
+            );
+        }
+
+        out.family(
+            "qpipe_handshake_failures_total",
+            "counter",
+            "QUIC connections which failed to establish",
+        );
+        out.sample(
+            "qpipe_handshake_failures_total",
+            &[],
+            self.handshake_failures.load(Ordering::Relaxed),
+        );
+
+        out.family(
+            "qpipe_rebinds_total",
+            "counter",
+            "moves to a new local socket, as the network changed",
+        );
+        out.sample(
+            "qpipe_rebinds_total",
+            &[],
+            self.rebinds.load(Ordering::Relaxed),
+        );
+
+        let paths: [(&str, &str, &str, PathGetter); 5] = [
+            (
+                "qpipe_connection_rtt_seconds",
+                "gauge",
+                "smoothed round trip time",
+                |p| p.rtt.as_secs_f64(),
+            ),
+            (
+                "qpipe_connection_cwnd_bytes",
+                "gauge",
+                "congestion window",
+                |p| p.cwnd as f64,
+            ),
+            (
+                "qpipe_connection_congestion_events_total",
+                "counter",
+                "congestion events",
+                |p| p.congestion_events as f64,
+            ),
+            (
+                "qpipe_connection_sent_packets_total",
+                "counter",
+                "packets sent",
+                |p| p.sent_packets as f64,
+            ),
+            (
+                "qpipe_connection_lost_packets_total",
+                "counter",
+                "packets lost",
+                |p| p.lost_packets as f64,
+            ),
+        ];
+        for (name, kind, help, value) in paths {
+            out.family(name, kind, help);
+            for (identity, peer, path) in &connections {
+                let peer = peer.to_string();
+                out.sample(
+                    name,
+                    &[("identity", identity), ("peer", &peer)],
+                    value(path),
+                );
+            }
+        }
+
+        out.0
+    }
+}
+
+// quinn doesn't export its `PathStats`
+struct PathSample {
+    rtt: Duration,
+    cwnd: u64,
+    congestion_events: u64,
+    sent_packets: u64,
+    lost_packets: u64,
+}
+
+type PathGetter = fn(&PathSample) -> f64;
+
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {name} {help}").expect("infallible");
+        writeln!(self.0, "# TYPE {name} {kind}").expect("infallible");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            self.0.push('{');
+            for (i, (key, value)) in labels.iter().enumerate() {
+                if i != 0 {
+                    self.0.push(',');
+                }
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                write!(self.0, "{key}=\"{value}\"").expect("infallible");
+            }
+            self.0.push('}');
+        }
+        writeln!(self.0, " {value}").expect("infallible");
+    }
+}
+
+/// a deliberately tiny http server, which only knows how to `GET /metrics`
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>, streams: Arc<Streams>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| anyhow!("binding metrics listener {addr}"))?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("metrics listener failed: {:?}", e);
+                    return;
+                }
+            };
+            let metrics = Arc::clone(&metrics);
+            let streams = Arc::clone(&streams);
+            tokio::spawn(async move {
+                if let Err(e) = handle_http(stream, &metrics, &streams).await {
+                    info!("metrics request from {peer} failed: {e:?}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle_http(mut stream: TcpStream, metrics: &Metrics, streams: &Streams) -> Result<()> {
+    // we only care about the request line, but read the whole head so the client isn't reset
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    timeout(REQUEST_TIMEOUT, async {
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            ensure!(head.len() < 16 * 1024, "overlong request");
+            let found = stream.read(&mut buf).await?;
+            ensure!(found != 0, "request truncated");
+            head.extend_from_slice(&buf[..found]);
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|_| anyhow!("timed out reading request"))??;
+
+    let (status, body) = if head.starts_with(b"GET /metrics ") {
+        ("200 OK", metrics.render(streams))
+    } else {
+        ("404 Not Found", "try /metrics\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[test]
+fn test_exposition_escaping() {
+    let mut out = Exposition::default();
+    out.family("qpipe_test", "gauge", "a test");
+    out.sample(
+        "qpipe_test",
+        &[("target", "a\"b\\c"), ("direction", "sent")],
+        7,
+    );
+    out.sample("qpipe_test", &[], 1.5);
+    assert_eq!(
+        "# HELP qpipe_test a test\n\
+         # TYPE qpipe_test gauge\n\
+         qpipe_test{target=\"a\\\"b\\\\c\",direction=\"sent\"} 7\n\
+         qpipe_test 1.5\n",
+        out.0
+    );
+}
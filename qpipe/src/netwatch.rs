@@ -0,0 +1,150 @@
+// Noticing that the machine has moved networks, so the client can move its connections
+// with it (see `client::rebind`). We listen on a netlink route socket for addresses and
+// routes coming and going; they tend to arrive in bursts, e.g. as dhcp finishes, so a
+// change is only reported once things have been quiet for a moment.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::unix::AsyncFd;
+use tokio::time::timeout;
+
+/// how long after the last netlink message a change is reported
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// the local addresses, or the routes between them, as they change
+pub struct Changes {
+    socket: AsyncFd<OwnedFd>,
+    buf: Vec<u8>,
+}
+
+impl Changes {
+    pub fn subscribe() -> Result<Self> {
+        // SAFETY: plain socket call; the fd is owned as soon as it's valid
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("opening netlink socket");
+        }
+        // SAFETY: just created, and nobody else has it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: all zeroes is a valid sockaddr_nl; the kernel picks our port id
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_IPV4_IFADDR
+            | libc::RTMGRP_IPV6_IFADDR
+            | libc::RTMGRP_IPV4_ROUTE
+            | libc::RTMGRP_IPV6_ROUTE) as u32;
+        // SAFETY: addr is a sockaddr_nl, of the length given
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error()).context("subscribing to netlink routes");
+        }
+
+        Ok(Changes {
+            socket: AsyncFd::new(fd)?,
+            buf: vec![0; 64 * 1024],
+        })
+    }
+
+    /// wait for the next change, once it's settled
+    pub async fn changed(&mut self) -> Result<()> {
+        while !self.recv().await? {}
+        while let Ok(received) = timeout(SETTLE, self.recv()).await {
+            received?;
+        }
+        Ok(())
+    }
+
+    /// one datagram; true if it said anything changed
+    async fn recv(&mut self) -> Result<bool> {
+        loop {
+            let mut guard = self.socket.readable().await?;
+            let buf = &mut self.buf;
+            let received = guard.try_io(|socket| {
+                // SAFETY: buf is valid for writes of its length
+                let len = unsafe {
+                    libc::recv(
+                        socket.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if len < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(len as usize)
+            });
+            match received {
+                Ok(Ok(len)) => return Ok(is_change(&self.buf[..len])),
+                // the kernel dropped some; we can't know what, so assume the worst
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(true),
+                Ok(Err(e)) => return Err(e).context("reading netlink socket"),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// whether any of the netlink messages in `buf` is an address or a route being added or
+/// removed; each starts with a `nlmsghdr`: its length, as a u32, then its type, as a u16
+fn is_change(mut buf: &[u8]) -> bool {
+    const HEADER_LEN: usize = mem::size_of::<libc::nlmsghdr>();
+    while buf.len() >= HEADER_LEN {
+        let len = u32::from_ne_bytes(buf[0..4].try_into().expect("four bytes")) as usize;
+        let kind = u16::from_ne_bytes(buf[4..6].try_into().expect("two bytes"));
+        if matches!(
+            kind,
+            libc::RTM_NEWADDR | libc::RTM_DELADDR | libc::RTM_NEWROUTE | libc::RTM_DELROUTE
+        ) {
+            return true;
+        }
+        if len < HEADER_LEN {
+            break;
+        }
+        // messages are padded to four bytes
+        let aligned = (len + 3) & !3;
+        buf = &buf[aligned.min(buf.len())..];
+    }
+    false
+}
+
+#[test]
+fn test_is_change() {
+    let message = |kind: u16, payload: usize| {
+        let len = mem::size_of::<libc::nlmsghdr>() + payload;
+        let mut buf = (len as u32).to_ne_bytes().to_vec();
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.resize((len + 3) & !3, 0);
+        buf
+    };
+    assert!(!is_change(&[]));
+    assert!(!is_change(&message(libc::NLMSG_NOOP as u16, 0)));
+    assert!(is_change(&message(libc::RTM_NEWADDR, 5)));
+
+    let mut buf = message(libc::NLMSG_NOOP as u16, 5);
+    buf.extend(message(libc::RTM_DELROUTE, 0));
+    assert!(is_change(&buf));
+
+    // a message claiming to be shorter than its header stops the walk
+    let mut buf = message(libc::NLMSG_NOOP as u16, 0);
+    buf[0..4].copy_from_slice(&2u32.to_ne_bytes());
+    buf.extend(message(libc::RTM_NEWADDR, 0));
+    assert!(!is_change(&buf));
+}
@@ -1,10 +1,59 @@
+// A package is everything a client needs to connect to a server: "qpipe2:" then the
+// base64 of a series of frames, in the v2 header format, ending with 'fini'. "qpipe1:"
+// packages use the v1 header format, and only ever contained the certs and the key.
+
+// 'scrt' - the server's CA cert, which we trust
+// cert bytes as der
+
+// 'ccrt' - client cert
+// cert bytes as der
+
+// 'ckey' - client key
+// key bytes as der (pkcs8)
+
+// 'ekey' - client key, encrypted with a passphrase
+// key bytes as encrypted pkcs8 der, see seal.rs
+
+// 'addr' - a server address, the first is the default
+// address_port_len: u8
+// address_port: [u8; address_port_len] e.g. "example.com:60010"
+
+// 'snin' - the name to ask for, and expect, in the server's certificate
+// [all utf-8 bytes]
+
+// 'spin' - the SHA-256 of the server's certificate, which must match
+// [u8; 32]
+
+// 'fwrd' - a default forward
+// source_len: u8
+// source: [u8; source_len] e.g. "localhost:2222"
+// target_len: u8
+// target: [u8; target_len] e.g. "example.com:22"
+
+// 'tprt' - transport settings, pairs of strings, see `Transport`
+// key_len: u8
+// key: [u8; key_len] e.g. "keep_alive_ms"
+// value_len: u8
+// value: [u8; value_len] e.g. "15000"
+// ...
+
+// 'expy' - when the package stops being valid
+// seconds since the unix epoch: u64
+
+// 'xt??' - extensions, see extension.rs
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use anyhow::{anyhow, bail, ensure, Result};
 // why
 use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
 use rustls::{Certificate, PrivateKey};
-use tokio::io::AsyncReadExt;
 
-use crate::frame::HeaderHeader;
+use crate::extension::{is_extension, Extensions, Seen};
+use crate::frame::{FourCc, HeaderHeader, Version};
+use crate::seal::Unlock;
+use crate::transport::Transport;
+use crate::{certs, wire};
 
 pub struct ClientCerts {
     pub server_cert: Certificate,
@@ -12,35 +61,321 @@ pub struct ClientCerts {
     pub client_key: PrivateKey,
 }
 
-pub async fn read_package(package: &str) -> Result<ClientCerts> {
-    let package_magic = "qpipe1:";
-    ensure!(
-        package.starts_with(package_magic),
-        "expected package magic, not {:?}...",
-        package.chars().take(20).collect::<String>()
-    );
-    let mut package = std::io::Cursor::new(base64.decode(&package[package_magic.len()..])?);
+#[derive(Clone, Debug)]
+pub struct Package {
+    /// of the header format; what it was read as, it's always written as v2
+    pub version: Version,
+    pub server_cert: Certificate,
+    pub client_cert: Certificate,
+    /// absent if the client generated its own key, and the package was issued for a CSR
+    pub client_key: Option<ClientKey>,
+    pub servers: Vec<String>,
+    pub server_name: Option<String>,
+    pub server_pin: Option<Vec<u8>>,
+    pub forwards: Vec<(String, String)>,
+    pub transport: Transport,
+    pub expires: Option<SystemTime>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ClientKey {
+    Plain(PrivateKey),
+    Sealed(Vec<u8>),
+}
+
+/// the SNI name used if the package doesn't say
+pub const DEFAULT_SERVER_NAME: &str = "localhost";
+
+pub async fn read_package(package: &str) -> Result<Package> {
+    read_package_with(package, &Extensions::default()).await
+}
+
+/// as `read_package`, passing any extension entries to their handlers
+pub async fn read_package_with(package: &str, extensions: &Extensions) -> Result<Package> {
+    let dispatch = extensions.dispatch(Seen::Package);
+    let (version, body) = if let Some(body) = package.strip_prefix("qpipe2:") {
+        (Version::V2, body)
+    } else if let Some(body) = package.strip_prefix("qpipe1:") {
+        (Version::V1, body)
+    } else {
+        bail!(
+            "expected package magic, not {:?}...",
+            package.chars().take(20).collect::<String>()
+        );
+    };
+    let mut package = std::io::Cursor::new(base64.decode(body.trim())?);
     let mut server_cert = None;
     let mut client_cert = None;
     let mut client_key = None;
+    let mut servers = Vec::new();
+    let mut server_name = None;
+    let mut server_pin = None;
+    let mut forwards = Vec::new();
+    let mut transport = Transport::default();
+    let mut expires = None;
 
     loop {
-        let hh = HeaderHeader::from(&mut package).await?;
-        let mut buf = vec![0u8; usize::from(hh.data_len)];
-        package.read_exact(&mut buf).await?;
+        let (hh, buf) = wire::read_frame(&mut package, version).await?;
         match &hh.four_cc {
             b"scrt" => server_cert = Some(rustls::Certificate(buf)),
             b"ccrt" => client_cert = Some(rustls::Certificate(buf)),
-            b"ckey" => client_key = Some(rustls::PrivateKey(buf)),
+            b"ckey" => client_key = Some(ClientKey::Plain(rustls::PrivateKey(buf))),
+            b"ekey" => client_key = Some(ClientKey::Sealed(buf)),
+            b"addr" => servers.extend(wire::parse_strings(&buf)?),
+            b"snin" => server_name = Some(String::from_utf8(buf)?),
+            b"spin" => {
+                ensure!(buf.len() == 32, "server pin must be a SHA-256");
+                server_pin = Some(buf);
+            }
+            b"fwrd" => match wire::parse_strings(&buf)?.as_slice() {
+                [source, target] => forwards.push((source.to_string(), target.to_string())),
+                other => bail!("a forward is a source and a target, not {other:?}"),
+            },
+            b"tprt" => transport = Transport::parse(&buf)?,
+            b"expy" => {
+                let secs = u64::from_le_bytes(
+                    buf.as_slice()
+                        .try_into()
+                        .map_err(|_| anyhow!("expiry must be a u64"))?,
+                );
+                expires = Some(UNIX_EPOCH + Duration::from_secs(secs));
+            }
             b"fini" => break,
-            // TODO: some kind of extension mechanism here
+            four_cc if is_extension(four_cc) => dispatch.handle(hh.four_cc, &buf)?,
             _ => bail!("unexpected packet in package: {:?}", hh),
         }
     }
 
-    Ok(ClientCerts {
+    Ok(Package {
+        version,
         server_cert: server_cert.ok_or_else(|| anyhow!("missing server_cert in package"))?,
         client_cert: client_cert.ok_or_else(|| anyhow!("missing client_cert in package"))?,
-        client_key: client_key.ok_or_else(|| anyhow!("missing client_key in package"))?,
+        client_key,
+        servers,
+        server_name,
+        server_pin,
+        forwards,
+        transport,
+        expires,
     })
 }
+
+impl Package {
+    pub fn new(server_cert: Certificate, client_cert: Certificate) -> Self {
+        Package {
+            version: Version::V2,
+            server_cert,
+            client_cert,
+            client_key: None,
+            servers: Vec::new(),
+            server_name: None,
+            server_pin: None,
+            forwards: Vec::new(),
+            transport: Transport::default(),
+            expires: None,
+        }
+    }
+
+    /// as a `qpipe2:` string
+    pub async fn encode(&self) -> Result<String> {
+        let mut entries: Vec<(FourCc, Vec<u8>)> = vec![
+            (*b"scrt", self.server_cert.0.clone()),
+            (*b"ccrt", self.client_cert.0.clone()),
+        ];
+        match &self.client_key {
+            Some(ClientKey::Plain(key)) => entries.push((*b"ckey", key.0.clone())),
+            Some(ClientKey::Sealed(sealed)) => entries.push((*b"ekey", sealed.clone())),
+            None => (),
+        }
+        for server in &self.servers {
+            entries.push((*b"addr", wire::encode_strings(&[server])?));
+        }
+        if let Some(name) = &self.server_name {
+            entries.push((*b"snin", name.as_bytes().to_vec()));
+        }
+        if let Some(pin) = &self.server_pin {
+            entries.push((*b"spin", pin.clone()));
+        }
+        for (source, target) in &self.forwards {
+            entries.push((*b"fwrd", wire::encode_strings(&[source, target])?));
+        }
+        if self.transport != Transport::default() {
+            entries.push((*b"tprt", self.transport.encode()?));
+        }
+        if let Some(expires) = self.expires {
+            let secs = expires.duration_since(UNIX_EPOCH)?.as_secs();
+            entries.push((*b"expy", secs.to_le_bytes().to_vec()));
+        }
+
+        let mut buf = Vec::new();
+        for (four_cc, data) in entries {
+            HeaderHeader {
+                four_cc,
+                data_len: data.len() as u64,
+            }
+            .write_all(&mut buf, Version::V2)
+            .await?;
+            buf.extend_from_slice(&data);
+        }
+        HeaderHeader::finished()
+            .write_all(&mut buf, Version::V2)
+            .await?;
+        Ok(format!("qpipe2:{}", base64.encode(buf)))
+    }
+
+    /// the certs to connect with, decrypting the key if necessary
+    pub fn client_certs(&self, unlock: &Unlock) -> Result<ClientCerts> {
+        let client_key = match &self.client_key {
+            Some(ClientKey::Plain(key)) => key.clone(),
+            Some(ClientKey::Sealed(sealed)) => {
+                PrivateKey(unlock.open(sealed, "the package's key")?)
+            }
+            None => bail!("the package has no client key; it was issued for a CSR, from `keygen`"),
+        };
+        Ok(self.client_certs_with(client_key))
+    }
+
+    /// the certs to connect with, for a package issued for our own key's CSR
+    pub fn client_certs_with(&self, client_key: PrivateKey) -> ClientCerts {
+        ClientCerts {
+            server_cert: self.server_cert.clone(),
+            client_cert: self.client_cert.clone(),
+            client_key,
+        }
+    }
+
+    pub fn server_name(&self) -> &str {
+        self.server_name.as_deref().unwrap_or(DEFAULT_SERVER_NAME)
+    }
+
+    pub fn ensure_current(&self) -> Result<()> {
+        if let Some(expires) = self.expires {
+            ensure!(
+                SystemTime::now() < expires,
+                "the package expired {} seconds ago",
+                expires.elapsed().unwrap_or_default().as_secs()
+            );
+        }
+        Ok(())
+    }
+
+    /// human-readable, for `package inspect`
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("format: {:?}", self.version),
+            format!(
+                "server certificate: {}",
+                certs::fingerprint(&self.server_cert)
+            ),
+            format!(
+                "client certificate: {}",
+                certs::fingerprint(&self.client_cert)
+            ),
+            format!(
+                "client key: {}",
+                match &self.client_key {
+                    Some(ClientKey::Plain(_)) => "included",
+                    Some(ClientKey::Sealed(_)) => "included, encrypted",
+                    None => "not included",
+                }
+            ),
+        ];
+        for server in &self.servers {
+            lines.push(format!("server: {server}"));
+        }
+        lines.push(format!("server name: {}", self.server_name()));
+        if let Some(pin) = &self.server_pin {
+            let pin = pin.iter().map(|b| format!("{b:02x}")).collect::<String>();
+            lines.push(format!("server pin: {pin}"));
+        }
+        for (source, target) in &self.forwards {
+            lines.push(format!("forward: {source} -> {target}"));
+        }
+        for (key, value) in self.transport.settings() {
+            lines.push(format!("transport: {key}={value}"));
+        }
+        if let Some(expires) = self.expires {
+            let secs = expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let state = match expires.duration_since(SystemTime::now()) {
+                Ok(left) => format!("in {}s", left.as_secs()),
+                Err(_) => "expired".to_string(),
+            };
+            lines.push(format!("expires: {secs} ({state})"));
+        }
+        lines
+    }
+}
+
+#[tokio::test]
+async fn test_package_round_trip() -> Result<()> {
+    let mut package = Package::new(
+        Certificate(b"server".to_vec()),
+        Certificate(b"client".to_vec()),
+    );
+    let (_csr, key) = certs::generate_client_certs()?;
+    package.client_key = Some(ClientKey::Sealed(crate::seal::seal(&key.0, "hunter2")?));
+    package.servers = vec!["example.com:60010".to_string(), "[::1]:60010".to_string()];
+    package.server_name = Some("example.com".to_string());
+    package.server_pin = Some(vec![7; 32]);
+    package.forwards = vec![("localhost:2222".to_string(), "localhost:22".to_string())];
+    package.transport.set("keep_alive_ms", "15000")?;
+    package.expires = Some(UNIX_EPOCH + Duration::from_secs(4_000_000_000));
+
+    let read = read_package(&package.encode().await?).await?;
+    assert_eq!(package.describe(), read.describe());
+    assert_eq!(
+        key,
+        read.client_certs(&Unlock::fixed("hunter2"))?.client_key
+    );
+    assert!(read.client_certs(&Unlock::default()).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_package_extensions() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    use crate::extension::Frame;
+
+    let mut buf = Vec::new();
+    for (four_cc, data) in [
+        (*b"xtzz", &b"nobody is listening"[..]),
+        (*b"scrt", b"server"),
+        (*b"xtmd", b"some metadata"),
+        (*b"ccrt", b"client"),
+        (*b"ckey", b"key"),
+    ] {
+        HeaderHeader {
+            four_cc,
+            data_len: data.len() as u64,
+        }
+        .write_all(&mut buf, Version::V2)
+        .await?;
+        buf.extend_from_slice(data);
+    }
+    HeaderHeader::finished()
+        .write_all(&mut buf, Version::V2)
+        .await?;
+    let package = format!("qpipe2:{}", base64.encode(buf));
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut extensions = Extensions::default();
+    let record = Arc::clone(&seen);
+    extensions.register(*b"xtmd", move |frame: &Frame<'_>| {
+        record.lock().expect("test").push(frame.data.to_vec());
+        Ok(())
+    })?;
+    assert!(extensions
+        .register(*b"nope", |_: &Frame<'_>| Ok(()))
+        .is_err());
+
+    let certs = read_package_with(&package, &extensions)
+        .await?
+        .client_certs(&Unlock::default())?;
+    assert_eq!(b"client", certs.client_cert.0.as_slice());
+    assert_eq!(vec![b"some metadata".to_vec()], *seen.lock().expect("test"));
+    Ok(())
+}
@@ -0,0 +1,211 @@
+// Packages saved under a name, so `connect` doesn't need them in its environment, where
+// they'd be visible in /proc/*/environ and shell history. They're either files in the state
+// dir, or secrets in the desktop keyring (the Secret Service, over D-Bus), which we talk to
+// through libsecret's `secret-tool`, rather than linking a D-Bus stack.
+
+use std::ffi::OsString;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{fs, io};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::state;
+
+/// the attribute every keyring item we store has, so ours can be told apart
+const KEYRING_APPLICATION: &str = "qpiped";
+
+/// names end up in paths and keyring attributes, so keep them boring
+pub fn validate_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)),
+        "profile names are letters, digits, '-', '_' and '.', not {name:?}"
+    );
+    Ok(())
+}
+
+/// profiles as files in `<state dir>/profiles`
+pub struct Profiles {
+    dir: PathBuf,
+}
+
+impl Profiles {
+    pub fn new(state_dir: impl AsRef<Path>) -> Self {
+        Profiles {
+            dir: state_dir.as_ref().join("profiles"),
+        }
+    }
+
+    pub fn save(&self, name: &str, package: &str) -> Result<()> {
+        validate_name(name)?;
+        // packages usually contain keys
+        state::ensure_private_dir(&self.dir)?;
+        let path = self.path(name);
+        state::write_private(&path, package.as_bytes())
+            .with_context(|| anyhow!("writing profile to {path:?}"))
+    }
+
+    pub fn load(&self, name: &str) -> Result<Option<String>> {
+        validate_name(name)?;
+        let path = self.path(name);
+        if path.exists() {
+            state::check_private(&path)?;
+        }
+        match fs::read_to_string(&path) {
+            Ok(package) => Ok(Some(package)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| anyhow!("reading profile from {path:?}")),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| anyhow!("listing {:?}", self.dir)),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("package") {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.package"))
+    }
+}
+
+/// profiles in the Secret Service, through `secret-tool`, or anything with the same
+/// command line: `store --label=.. attr value ..` reading the secret from stdin, and
+/// `lookup attr value ..` printing it, or exiting with 1 if there's no such item
+pub struct Keyring {
+    tool: OsString,
+}
+
+impl Default for Keyring {
+    fn default() -> Self {
+        Keyring::new("secret-tool")
+    }
+}
+
+impl Keyring {
+    pub fn new(tool: impl Into<OsString>) -> Self {
+        Keyring { tool: tool.into() }
+    }
+
+    pub fn save(&self, name: &str, package: &str) -> Result<()> {
+        validate_name(name)?;
+        let mut child = self
+            .command()
+            .arg("store")
+            .arg(format!("--label=qpiped package {name}"))
+            .args(attributes(name))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| anyhow!("running {:?}", self.tool))?;
+        child
+            .stdin
+            .take()
+            .expect("piped above")
+            .write_all(package.as_bytes())?;
+        let status = child.wait()?;
+        ensure!(
+            status.success(),
+            "{:?} failed to store the package: {status}",
+            self.tool
+        );
+        Ok(())
+    }
+
+    pub fn load(&self, name: &str) -> Result<Option<String>> {
+        validate_name(name)?;
+        let output = self
+            .command()
+            .arg("lookup")
+            .args(attributes(name))
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| anyhow!("running {:?}", self.tool))?;
+        match output.status.code() {
+            Some(0) => Ok(Some(String::from_utf8(output.stdout)?)),
+            Some(1) if output.stderr.is_empty() => Ok(None),
+            _ => bail!(
+                "{:?} failed to look up the package: {}: {}",
+                self.tool,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        }
+    }
+
+    fn command(&self) -> Command {
+        Command::new(&self.tool)
+    }
+}
+
+fn attributes(name: &str) -> [&str; 4] {
+    ["application", KEYRING_APPLICATION, "profile", name]
+}
+
+#[test]
+fn test_profile_files() -> Result<()> {
+    let state_dir = tempfile::tempdir()?;
+    let profiles = Profiles::new(&state_dir);
+    assert_eq!(None, profiles.load("work")?);
+    profiles.save("work", "qpipe2:AAAA")?;
+    profiles.save("home", "qpipe2:BBBB")?;
+    assert_eq!(Some("qpipe2:AAAA".to_string()), profiles.load("work")?);
+    assert_eq!(vec!["home", "work"], profiles.list()?);
+    assert!(profiles.save("../escape", "qpipe2:CCCC").is_err());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_keyring_stand_in() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // a secret-tool which keeps secrets in files named after their attributes
+    let dir = tempfile::tempdir()?;
+    let tool = dir.path().join("secret-tool");
+    fs::write(
+        &tool,
+        format!(
+            r#"#!/bin/sh
+store={store:?}
+cmd=$1; shift
+[ "$cmd" = store ] && shift
+key=$(echo "$@" | tr ' ' _)
+case $cmd in
+store) cat > "$store/$key" ;;
+lookup) [ -f "$store/$key" ] || exit 1; cat "$store/$key" ;;
+*) echo "unknown command $cmd" >&2; exit 2 ;;
+esac
+"#,
+            store = dir.path()
+        ),
+    )?;
+    fs::set_permissions(&tool, fs::Permissions::from_mode(0o700))?;
+
+    let keyring = Keyring::new(&tool);
+    assert_eq!(None, keyring.load("work")?);
+    keyring.save("work", "qpipe2:AAAA")?;
+    assert_eq!(Some("qpipe2:AAAA".to_string()), keyring.load("work")?);
+    assert!(Keyring::new(dir.path().join("missing"))
+        .load("work")
+        .is_err());
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// Passphrase-encrypted private keys, on disk and in packages. They're standard encrypted
+// PKCS#8: PBES2, with scrypt (log2(N) = 15, r = 8, p = 1) then AES-256-CBC.
+
+use std::fmt;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use pkcs8::der::Decode as _;
+use pkcs8::{EncryptedPrivateKeyInfo, PrivateKeyInfo};
+use rand_core::OsRng;
+
+/// encrypt a PKCS#8 DER private key
+pub fn seal(plain: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    ensure!(
+        !passphrase.is_empty(),
+        "an empty passphrase protects nothing"
+    );
+    let info = PrivateKeyInfo::try_from(plain).map_err(|e| anyhow!("parsing key: {e}"))?;
+    let sealed = info
+        .encrypt(OsRng, passphrase)
+        .map_err(|e| anyhow!("encrypting key: {e}"))?;
+    Ok(sealed.as_bytes().to_vec())
+}
+
+/// decrypt a key from `seal`
+pub fn open(sealed: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let info = EncryptedPrivateKeyInfo::from_der(sealed)
+        .map_err(|e| anyhow!("parsing encrypted key: {e}"))?;
+    let plain = info
+        .decrypt(passphrase)
+        .map_err(|_| anyhow!("wrong passphrase, or corrupt key"))?;
+    Ok(plain.as_bytes().to_vec())
+}
+
+/// whether this key needs a passphrase, i.e. came from `seal`
+pub fn is_sealed(der: &[u8]) -> bool {
+    EncryptedPrivateKeyInfo::from_der(der).is_ok()
+}
+
+/// Somewhere to get a passphrase from, only asked when a key actually needs one, then
+/// remembered, so unlocking several keys doesn't e.g. prompt several times.
+#[derive(Default)]
+pub struct Unlock {
+    #[allow(clippy::type_complexity)]
+    source: Option<Box<dyn Fn(&str) -> Result<String> + Send + Sync>>,
+    cached: Mutex<Option<String>>,
+}
+
+impl Unlock {
+    /// `source` is told what the passphrase is for, e.g. to prompt with
+    pub fn new(source: impl Fn(&str) -> Result<String> + Send + Sync + 'static) -> Self {
+        Unlock {
+            source: Some(Box::new(source)),
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn fixed(passphrase: impl Into<String>) -> Self {
+        let passphrase = passphrase.into();
+        Unlock::new(move |_| Ok(passphrase.to_string()))
+    }
+
+    pub fn passphrase(&self, what: &str) -> Result<String> {
+        let mut cached = self.cached.lock().expect("poisoned");
+        if let Some(passphrase) = &*cached {
+            return Ok(passphrase.to_string());
+        }
+        let source = self
+            .source
+            .as_ref()
+            .ok_or_else(|| anyhow!("{what} is encrypted, and no passphrase was provided"))?;
+        let passphrase = source(what).with_context(|| anyhow!("getting passphrase for {what}"))?;
+        *cached = Some(passphrase.to_string());
+        Ok(passphrase)
+    }
+
+    /// decrypt `key` if it's sealed, otherwise pass it through
+    pub fn open(&self, key: &[u8], what: &str) -> Result<Vec<u8>> {
+        if !is_sealed(key) {
+            return Ok(key.to_vec());
+        }
+        let passphrase = self.passphrase(what)?;
+        open(key, &passphrase).with_context(|| anyhow!("decrypting {what}"))
+    }
+}
+
+impl fmt::Debug for Unlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Unlock")
+            .field("source", &self.source.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+#[test]
+fn test_seal_round_trip() -> Result<()> {
+    let (_csr, key) = crate::certs::generate_client_certs()?;
+    let sealed = seal(&key.0, "hunter2")?;
+    assert!(is_sealed(&sealed));
+    assert!(!is_sealed(&key.0));
+    assert_eq!(key.0, open(&sealed, "hunter2")?);
+    assert!(open(&sealed, "hunter3").is_err());
+
+    assert!(Unlock::default().open(&sealed, "the key").is_err());
+    assert_eq!(key.0, Unlock::default().open(&key.0, "the key")?);
+    assert_eq!(key.0, Unlock::fixed("hunter2").open(&sealed, "the key")?);
+    Ok(())
+}
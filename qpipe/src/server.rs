@@ -1,45 +1,221 @@
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use futures_util::stream::{self, StreamExt as _};
 use log::{error, info, warn};
+use quinn::{Connection, Endpoint};
 use rustls::server::AllowAnyAuthenticatedClient;
 use rustls::{Certificate, PrivateKey, RootCertStore};
-use tokio::net::{lookup_host, TcpSocket};
-use tokio::try_join;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::timeout;
 
-use super::frame::copy_framing;
-use super::frame::copy_unframing;
-use super::frame::HeaderHeader;
-use super::wire;
+use super::access_log::{AccessLog, Record};
+use super::extension::{is_extension, Extensions, Seen};
+use super::frame::{
+    alpn_protocols, describe_versions, no_shared_version, reset_tcp, splice, wants_tcp_reset,
+};
+use super::frame::{
+    HeaderHeader, Version, CLOSE_LIMITED, CLOSE_SHUTDOWN, ERR_CONNECT, ERR_LIMITED, ERR_RESOLUTION,
+    ERR_UNRECOGNISED_FRAME, ERR_UNSUPPORTED_PROTOCOL, FLAG_RAW, SUPPORTED_FLAGS,
+};
+use super::limits::{Limits, Quotas};
+use super::metrics::{self, Metrics};
+use super::sessions::Sessions;
+use super::shaping::{Shape, Shaper, Shaping};
+use super::streams::{Accepted, Counting, Streams};
+use super::transport::Transport;
+use super::{certs, wire};
 
 pub struct Certs {
     pub server_key: PrivateKey,
     pub server_chain: Vec<Certificate>,
 }
 
-pub async fn run(certs: Certs, addr: SocketAddr) -> Result<()> {
-    let mut root = RootCertStore::empty();
-    for cert in &certs.server_chain {
-        root.add(cert)?;
+pub struct Options {
+    /// how long to wait for established streams to finish, after being asked to shut down
+    pub drain_timeout: Duration,
+    /// serve prometheus metrics over http on this address
+    pub metrics: Option<SocketAddr>,
+    /// write a JSON line per finished stream here, or to stdout for `-`
+    pub access_log: Option<PathBuf>,
+    /// handlers for 'xt??' frames sent by clients
+    pub extensions: Extensions,
+    /// accept IPv4 on IPv6 addresses (i.e. turn off IPV6_V6ONLY), so `[::]` is enough;
+    /// otherwise, IPv6 addresses are only IPv6, whatever the system default
+    pub dual_stack: bool,
+    /// what each client identity may have open, and move
+    pub limits: Limits,
+    /// bytes per second, across every stream
+    pub bandwidth: Option<u64>,
+    /// for streams to these targets, by `address_port`, as the client asked for it
+    pub shapes: HashMap<String, Shape>,
+    pub transport: Transport,
+    /// already bound, e.g. by systemd; served as well as the addresses
+    pub sockets: Vec<std::net::UdpSocket>,
+    /// keep clients' resumable sessions here, so they can resume, in 0-RTT, after we
+    /// restart; otherwise, in memory. They're secrets, like the server's key
+    pub sessions: Option<PathBuf>,
+    /// tell systemd when we're ready, stopping, and still alive
+    #[cfg(unix)]
+    pub notify: crate::systemd::Notify,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            drain_timeout: Duration::from_secs(30),
+            metrics: None,
+            access_log: None,
+            extensions: Extensions::default(),
+            dual_stack: false,
+            limits: Limits::default(),
+            bandwidth: None,
+            shapes: HashMap::new(),
+            transport: Transport::default(),
+            sockets: Vec::new(),
+            sessions: None,
+            #[cfg(unix)]
+            notify: Default::default(),
+        }
     }
+}
+
+/// shared between every connection and stream
+#[derive(Default)]
+struct State {
+    streams: Arc<Streams>,
+    metrics: Arc<Metrics>,
+    access_log: Option<AccessLog>,
+    extensions: Extensions,
+    quotas: Quotas,
+    bandwidth: Option<Arc<Shaper>>,
+    shapes: HashMap<String, Shape>,
+    // for the `shapes` with a bandwidth
+    shapers: HashMap<String, Arc<Shaper>>,
+}
+
+/// serve, on all of `addrs`, until `shutdown` completes, then drain
+pub async fn run(
+    certs: Certs,
+    addrs: Vec<SocketAddr>,
+    options: Options,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    // clients' certificates are issued by our CA, which ends the chain
+    let mut root = RootCertStore::empty();
+    root.add(
+        certs
+            .server_chain
+            .last()
+            .ok_or_else(|| anyhow!("empty server chain"))?,
+    )?;
     let mut server_crypto = rustls::ServerConfig::builder()
         .with_safe_defaults()
         .with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(root)))
         .with_single_cert(certs.server_chain, certs.server_key)?;
 
     server_crypto.alpn_protocols = alpn_protocols();
+    let sessions = match &options.sessions {
+        Some(path) => Sessions::persisted(path)?,
+        None => Sessions::in_memory(),
+    };
+    server_crypto.session_storage = Arc::clone(&sessions) as _;
+    // one for the next reconnect, and a spare; each works once
+    server_crypto.send_tls13_tickets = 2;
+    // QUIC allows nothing in between
+    server_crypto.max_early_data_size = u32::MAX;
 
     let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
     server_config.use_retry(true);
+    server_config.transport_config(Arc::new(options.transport.config()?));
+
+    ensure!(
+        !addrs.is_empty() || !options.sockets.is_empty(),
+        "no addresses to listen on"
+    );
+    if options.dual_stack {
+        // `[::]` takes IPv4 for its port too, so nothing else can
+        for v4 in addrs.iter().filter(|addr| addr.is_ipv4()) {
+            ensure!(
+                !addrs.contains(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), v4.port())),
+                "can't listen on {v4} as well as [::]:{}, which is dual-stack",
+                v4.port()
+            );
+        }
+    }
+    let mut servers = addrs
+        .iter()
+        .map(|addr| bind(*addr, options.dual_stack, server_config.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    for socket in options.sockets {
+        servers.push(endpoint(socket, server_config.clone())?);
+    }
+    let state = Arc::new(State {
+        access_log: options
+            .access_log
+            .as_deref()
+            .map(AccessLog::open)
+            .transpose()?,
+        extensions: options.extensions,
+        quotas: Quotas::new(options.limits),
+        bandwidth: options.bandwidth.map(|rate| Shaper::new("global", rate)),
+        shapers: Shaper::for_shapes("target", &options.shapes),
+        shapes: options.shapes,
+        ..State::default()
+    });
+    let streams = &state.streams;
+    let (start_draining, draining) = watch::channel(false);
+
+    if let Some(addr) = options.metrics {
+        metrics::serve(addr, Arc::clone(&state.metrics), Arc::clone(streams)).await?;
+    }
+
+    #[cfg(unix)]
+    let heartbeat = {
+        let local_addrs = servers
+            .iter()
+            .filter_map(|server| server.local_addr().ok())
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        options.notify.ready(&format!("serving on {local_addrs}"));
+        let streams = Arc::clone(streams);
+        options
+            .notify
+            .spawn_heartbeat(move || format!("serving {} streams", streams.len()))
+    };
 
-    let server = quinn::Endpoint::server(server_config, addr)?;
+    let saver = sessions.spawn_saver();
+    tokio::pin!(shutdown);
+
+    // everything arriving on any of the endpoints, all handled alike
+    let mut incoming = stream::select_all(servers.iter().map(|server| {
+        Box::pin(stream::unfold(server.clone(), |server| async move {
+            server.accept().await.map(|conn| (conn, server))
+        }))
+    }));
 
     // connection here is more like a bind in traditional networking;
     // as there are multiple, independent "connections" to it over its life
-    while let Some(conn) = server.accept().await {
+    loop {
+        let conn = select! {
+            conn = incoming.next() => match conn {
+                Some(conn) => conn,
+                None => break,
+            },
+            () = &mut shutdown => break,
+        };
         // dunno what this explicit 'fut' is about; cargo-culted from the example
-        let fut = handle_connection(conn);
+        let fut = handle_connection(conn, Arc::clone(&state), draining.clone());
         tokio::spawn(async move {
             if let Err(e) = fut.await {
                 error!("connection handling failure, {:?}", e);
@@ -47,15 +223,113 @@ pub async fn run(certs: Certs, addr: SocketAddr) -> Result<()> {
         });
     }
 
+    info!("shutting down, draining {} streams", streams.len());
+    #[cfg(unix)]
+    {
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.abort();
+        }
+        options
+            .notify
+            .stopping(&format!("draining {} streams", streams.len()));
+    }
+    for server in &servers {
+        server.reject_new_connections();
+    }
+    start_draining.send_replace(true);
+    if timeout(options.drain_timeout, streams.drained())
+        .await
+        .is_err()
+    {
+        warn!(
+            "drain timeout expired, abandoning {} streams",
+            streams.len()
+        );
+    }
+    for server in &servers {
+        server.close(CLOSE_SHUTDOWN.into(), b"server shutting down");
+    }
+    for server in &servers {
+        server.wait_idle().await;
+    }
+    if let Some(saver) = saver {
+        saver.abort();
+    }
+    sessions.save()?;
+
     Ok(())
 }
 
-async fn handle_connection(conn: quinn::Connecting) -> Result<()> {
-    let conn = conn.await.context("handshake failed")?;
+fn bind(addr: SocketAddr, dual_stack: bool, config: quinn::ServerConfig) -> Result<Endpoint> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket
+        .bind(&addr.into())
+        .with_context(|| anyhow!("binding {addr}"))?;
+    endpoint(socket.into(), config)
+}
+
+fn endpoint(socket: std::net::UdpSocket, config: quinn::ServerConfig) -> Result<Endpoint> {
+    socket.set_nonblocking(true)?;
+    let endpoint = Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(config),
+        socket,
+        quinn::default_runtime().ok_or_else(|| anyhow!("no async runtime"))?,
+    )?;
+    info!("listening on {}", endpoint.local_addr()?);
+    Ok(endpoint)
+}
+
+async fn handle_connection(
+    conn: quinn::Connecting,
+    state: Arc<State>,
+    mut draining: watch::Receiver<bool>,
+) -> Result<()> {
+    let remote = conn.remote_address();
+    // a resuming client's early data waits until the handshake's complete, so a replay of it,
+    // which can't complete the handshake, is never acted on
+    let conn = match conn.await {
+        Ok(conn) => conn,
+        Err(e) if no_shared_version(&e) => {
+            state.metrics.handshake_failed();
+            return Err(e).with_context(|| {
+                anyhow!(
+                    "handshake with {remote} failed: it speaks no protocol version we do ({})",
+                    describe_versions()
+                )
+            });
+        }
+        Err(e) => {
+            state.metrics.handshake_failed();
+            return Err(e).context("handshake failed");
+        }
+    };
+    let identity = certs::peer_fingerprint(&conn);
+    let version = Version::negotiated(&conn);
+    info!(
+        "{identity} connected from {}, {version:?}",
+        conn.remote_address()
+    );
+    let _permit = match state.quotas.connection(&identity) {
+        Ok(permit) => permit,
+        Err(exceeded) => {
+            state.metrics.limit_exceeded(exceeded);
+            conn.close(CLOSE_LIMITED.into(), exceeded.reason().as_bytes());
+            bail!("refusing {identity}: {}", exceeded.reason());
+        }
+    };
+    state.metrics.connection_opened(identity.to_string(), &conn);
 
     loop {
         info!("server stream noticed");
-        let stream = match conn.accept_bi().await {
+        let stream = select! {
+            stream = conn.accept_bi() => stream,
+            _ = draining.wait_for(|draining| *draining) => break,
+        };
+        let stream = match stream {
             Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
                 info!("app closed");
                 return Ok(());
@@ -64,88 +338,187 @@ async fn handle_connection(conn: quinn::Connecting) -> Result<()> {
             Ok(s) => s,
         };
 
+        // counted from now, so a shutdown waits for it to be connected, and finished
+        let accepted = state.streams.accept();
         // dunno what this explicit 'fut' is about; cargo-culted from the example
-        let fut = handle_stream(stream);
+        let fut = handle_stream(
+            stream,
+            accepted,
+            version,
+            identity.to_string(),
+            conn.remote_address(),
+            Arc::clone(&state),
+        );
         tokio::spawn(async move {
             if let Err(e) = fut.await {
                 error!("stream failed: {:?}", e);
             }
         });
     }
+
+    go_away(&conn, version).await
 }
 
-// apparently this is the supported .. draft version?
-// https://github.com/quinn-rs/quinn/blob/6fc46aefc65aeb3dd2d059ea6aabaf7a6c2f5bdb/quinn/examples/common/mod.rs#L69
-pub fn alpn_protocols() -> Vec<Vec<u8>> {
-    vec![b"hq-29".to_vec()]
+/// tell the client to take its new streams elsewhere; the ones it already has are left running
+async fn go_away(conn: &Connection, version: Version) -> Result<()> {
+    conn.set_max_concurrent_bi_streams(0u32.into());
+    let mut control = conn.open_uni().await?;
+    HeaderHeader::empty(*b"gway")
+        .write_all(&mut control, version)
+        .await?;
+    control.finish().await?;
+    Ok(())
 }
 
 async fn handle_stream(
     (mut framed_to, mut framed_from): (quinn::SendStream, quinn::RecvStream),
+    accepted: Accepted,
+    version: Version,
+    identity: String,
+    peer: SocketAddr,
+    state: Arc<State>,
 ) -> Result<()> {
-    let mut buf = vec![0u8; usize::from(u16::MAX)];
+    let started = SystemTime::now();
+    let dispatch = state.extensions.dispatch(Seen::Stream {
+        identity: &identity,
+        peer,
+    });
 
     let establish = loop {
-        // read_frame?
-        let req = HeaderHeader::from(&mut framed_from).await?;
-        let buf = &mut buf[..usize::from(req.data_len)];
-        framed_from.read_exact(buf).await?;
+        let (req, buf) = wire::read_frame(&mut framed_from, version).await?;
 
         // handle_common_or(b"con1", CloseOnError)?
         match &req.four_cc {
             b"ping" => {
-                HeaderHeader::pong().write_all(&mut framed_to).await?;
+                HeaderHeader::pong()
+                    .write_all(&mut framed_to, version)
+                    .await?;
                 framed_to.write_all(&buf).await?;
             }
             b"con1" => {
-                break wire::parse_establish(buf)?;
+                break wire::parse_establish(&buf)?;
             }
+            four_cc if is_extension(four_cc) => dispatch.handle(req.four_cc, &buf)?,
             _ => {
                 warn!(
                     "unsupported client request: {:?}, {:?}...",
                     req,
-                    String::from_utf8_lossy(buf)
+                    String::from_utf8_lossy(&buf)
                         .chars()
                         .take(30)
                         .collect::<String>()
                 );
-                wire::write_error(&mut framed_to, 1, "unrecognised frame").await?
+                wire::write_error(
+                    &mut framed_to,
+                    version,
+                    ERR_UNRECOGNISED_FRAME,
+                    "unrecognised frame",
+                )
+                .await?
             }
         }
     };
 
-    ensure!(
-        establish.protocol == b't',
-        "only tcp is supported, not {:?}",
-        establish.protocol
+    let mut record = Record::new(
+        identity.to_string(),
+        peer.to_string(),
+        establish.address_port.to_string(),
+        started,
     );
 
-    // TODO: return these errors to the client cleanly?
-    let mut resolution = lookup_host(&establish.address_port).await?;
-    // TODO: try multiple addresses?
-    let picked = resolution
-        .next()
-        .ok_or_else(|| anyhow!("no resolution for {:?}", establish.address_port))?;
-    let plain = match picked.ip() {
-        IpAddr::V4(_) => TcpSocket::new_v4()?,
-        IpAddr::V6(_) => TcpSocket::new_v6()?,
-    }
-    .connect(picked)
-    .await?;
-
-    HeaderHeader::empty(*b"okay")
-        .write_all(&mut framed_to)
-        .await?;
+    // checked before connecting, so a refused stream costs nothing but this
+    let plain = match state.quotas.stream(&identity) {
+        Err(exceeded) => {
+            state.metrics.limit_exceeded(exceeded);
+            Err((ERR_LIMITED, anyhow!("{}", exceeded.reason())))
+        }
+        Ok(_) if establish.protocol != b't' => Err((
+            ERR_UNSUPPORTED_PROTOCOL,
+            anyhow!("only tcp is supported, not {:?}", establish.protocol),
+        )),
+        Ok(permit) => connect_plain(&establish.address_port)
+            .await
+            .map(|plain| (plain, permit)),
+    };
+    let (plain, permit) = match plain {
+        Ok(plain) => plain,
+        Err((code, e)) => {
+            state.metrics.connect_failed(code);
+            if let Some(access_log) = &state.access_log {
+                record.refused(started, code, &e);
+                access_log.record(&record);
+            }
+            wire::write_error(&mut framed_to, version, code, &format!("{e:#}")).await?;
+            framed_to.finish().await?;
+            return Err(e);
+        }
+    };
 
-    let (mut plain_from, mut plain_to) = plain.into_split();
+    let flags = establish.flags & SUPPORTED_FLAGS;
+    wire::write_okay(&mut framed_to, version, flags).await?;
 
-    try_join!(
-        async { copy_framing(&mut plain_from, &mut framed_to).await },
-        async { copy_unframing(&mut framed_from, &mut plain_to).await }
-    )?;
+    record.resolved = plain.peer_addr().ok().map(|addr| addr.to_string());
+    let guard = accepted.register(peer.to_string(), establish.address_port.to_string());
+    let (plain_from, plain_to) = plain.into_split();
+    let mut plain_from = Counting::new(plain_from, &guard.info.read);
+    let mut plain_to = Counting::new(plain_to, &guard.info.written);
 
-    framed_to.finish().await?;
+    let mut shaping = Shaping::new(Arc::clone(&state.metrics));
+    // the client's choice only orders its own streams, unless we've overridden it
+    shaping.priority = state
+        .shapes
+        .get(&establish.address_port)
+        .and_then(|shape| shape.priority)
+        .unwrap_or(establish.priority);
+    shaping.limit(permit.bandwidth());
+    shaping.limit(state.shapers.get(&establish.address_port));
+    shaping.limit(state.bandwidth.as_ref());
+
+    let res = splice(
+        version,
+        flags & FLAG_RAW != 0,
+        dispatch,
+        &shaping,
+        &mut plain_from,
+        &mut plain_to,
+        &mut framed_to,
+        &mut framed_from,
+    )
+    .await;
+    if wants_tcp_reset(&res) {
+        reset_tcp(plain_from.into_inner(), plain_to.into_inner());
+    }
+
+    if let Some(access_log) = &state.access_log {
+        record.bytes_sent = guard.info.read.load(Ordering::Relaxed);
+        record.bytes_received = guard.info.written.load(Ordering::Relaxed);
+        record.finish(started, &res);
+        access_log.record(&record);
+    }
 
     info!("closed?");
-    Ok(())
+    res
+}
+
+/// resolve and connect, or the `errm` code and reason we couldn't
+async fn connect_plain(address_port: &str) -> Result<TcpStream, (u32, Error)> {
+    let mut resolution = lookup_host(address_port)
+        .await
+        .map_err(|e| (ERR_RESOLUTION, e.into()))?;
+    // TODO: try multiple addresses?
+    let picked = resolution.next().ok_or_else(|| {
+        (
+            ERR_RESOLUTION,
+            anyhow!("no resolution for {:?}", address_port),
+        )
+    })?;
+    let socket = match picked.ip() {
+        IpAddr::V4(_) => TcpSocket::new_v4(),
+        IpAddr::V6(_) => TcpSocket::new_v6(),
+    }
+    .map_err(|e| (ERR_CONNECT, e.into()))?;
+    socket
+        .connect(picked)
+        .await
+        .map_err(|e| (ERR_CONNECT, e.into()))
 }
@@ -0,0 +1,217 @@
+// TLS sessions on the server, for clients to resume. Each ticket rustls issues names one of
+// these, and resuming takes it, so a ticket works once: that's what lets rustls accept 0-RTT
+// data (RFC 8446, section 8.1). Even so, the server doesn't act on a stream until the
+// handshake is complete, and clients don't send 'con1' before then, so replayed early data
+// never opens anything. Given a path, sessions are saved there, every few seconds if they've
+// changed, and on shutdown, and loaded again at start, so clients can resume after a restart.
+
+// file format, all little endian:
+// version: u8 (1)
+// then, for each session:
+// issued: u64, seconds since the unix epoch
+// ticket_len: u8
+// ticket: [u8; ticket_len]
+// value_len: u32
+// value: [u8; value_len], as rustls encodes it
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use log::{info, warn};
+use rustls::server::StoresServerSessions;
+use tokio::task::JoinHandle;
+
+use crate::state;
+
+/// what rustls tells clients a ticket is good for, when it isn't encrypting them itself
+const LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// the most kept at once; the oldest go first
+const MAX_SESSIONS: usize = 4096;
+
+/// how often changes are saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+const FORMAT_VERSION: u8 = 1;
+
+/// each session's rustls encoding, and when it was issued
+type ByTicket = HashMap<Vec<u8>, (SystemTime, Vec<u8>)>;
+
+/// resumable sessions, for rustls's `session_storage`
+#[derive(Default)]
+pub struct Sessions {
+    path: Option<PathBuf>,
+    sessions: Mutex<ByTicket>,
+    dirty: AtomicBool,
+}
+
+impl Sessions {
+    /// lost on exit
+    pub fn in_memory() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// saved to `path`, and loaded from it now, if it's there
+    pub fn persisted(path: impl Into<PathBuf>) -> Result<Arc<Self>> {
+        let path = path.into();
+        let mut sessions = HashMap::new();
+        if path.exists() {
+            state::check_private(&path)?;
+            let buf = std::fs::read(&path).with_context(|| anyhow!("reading {path:?}"))?;
+            // they're only an optimisation; clients fall back to a full handshake
+            match parse(&buf) {
+                Ok(parsed) => sessions = parsed,
+                Err(e) => warn!("discarding saved sessions in {path:?}: {e:#}"),
+            }
+            info!("loaded {} resumable sessions", sessions.len());
+        }
+        Ok(Arc::new(Sessions {
+            path: Some(path),
+            sessions: Mutex::new(sessions),
+            dirty: AtomicBool::new(false),
+        }))
+    }
+
+    /// write them out, if anything's changed since last time, and there's somewhere to
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let buf = encode(&self.sessions.lock().expect("poisoned"));
+        state::write_private(path, &buf).inspect_err(|_| self.dirty.store(true, Ordering::Release))
+    }
+
+    /// until aborted, `save` every few seconds, if there's anywhere to
+    pub fn spawn_saver(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        self.path.as_ref()?;
+        let sessions = Arc::clone(self);
+        Some(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(SAVE_INTERVAL);
+            loop {
+                ticks.tick().await;
+                let sessions = Arc::clone(&sessions);
+                match tokio::task::spawn_blocking(move || sessions.save()).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => warn!("saving sessions: {e:#}"),
+                    Err(e) => warn!("saving sessions: {e}"),
+                }
+            }
+        }))
+    }
+}
+
+impl StoresServerSessions for Sessions {
+    fn put(&self, ticket: Vec<u8>, value: Vec<u8>) -> bool {
+        if ticket.len() > usize::from(u8::MAX) {
+            return false;
+        }
+        let now = SystemTime::now();
+        let mut sessions = self.sessions.lock().expect("poisoned");
+        if sessions.len() >= MAX_SESSIONS {
+            sessions.retain(|_, (issued, _)| !expired(*issued, now));
+        }
+        if sessions.len() >= MAX_SESSIONS {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, (issued, _))| *issued)
+                .map(|(ticket, _)| ticket.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(ticket, (now, value));
+        self.dirty.store(true, Ordering::Release);
+        true
+    }
+
+    fn get(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let sessions = self.sessions.lock().expect("poisoned");
+        let (issued, value) = sessions.get(ticket)?;
+        (!expired(*issued, SystemTime::now())).then(|| value.clone())
+    }
+
+    fn take(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let (issued, value) = self.sessions.lock().expect("poisoned").remove(ticket)?;
+        self.dirty.store(true, Ordering::Release);
+        (!expired(issued, SystemTime::now())).then_some(value)
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+fn expired(issued: SystemTime, now: SystemTime) -> bool {
+    now.duration_since(issued).is_ok_and(|age| age > LIFETIME)
+}
+
+fn encode(sessions: &ByTicket) -> Vec<u8> {
+    let mut buf = vec![FORMAT_VERSION];
+    for (ticket, (issued, value)) in sessions {
+        let issued = issued.duration_since(UNIX_EPOCH).unwrap_or_default();
+        buf.extend_from_slice(&issued.as_secs().to_le_bytes());
+        buf.push(u8::try_from(ticket.len()).expect("checked in put"));
+        buf.extend_from_slice(ticket);
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(value);
+    }
+    buf
+}
+
+fn parse(buf: &[u8]) -> Result<ByTicket> {
+    let (&version, mut buf) = buf.split_first().ok_or_else(|| anyhow!("empty file"))?;
+    ensure!(version == FORMAT_VERSION, "unsupported version {version}");
+    let now = SystemTime::now();
+    let mut sessions = HashMap::new();
+    while !buf.is_empty() {
+        let issued = u64::from_le_bytes(split(&mut buf, 8)?.try_into()?);
+        let issued = UNIX_EPOCH + Duration::from_secs(issued);
+        let ticket_len = usize::from(split(&mut buf, 1)?[0]);
+        let ticket = split(&mut buf, ticket_len)?.to_vec();
+        let value_len = u32::from_le_bytes(split(&mut buf, 4)?.try_into()?);
+        let value = split(&mut buf, usize::try_from(value_len)?)?.to_vec();
+        if !expired(issued, now) {
+            sessions.insert(ticket, (issued, value));
+        }
+    }
+    Ok(sessions)
+}
+
+/// the first `len` bytes of `buf`, moving it past them
+fn split<'b>(buf: &mut &'b [u8], len: usize) -> Result<&'b [u8]> {
+    ensure!(buf.len() >= len, "truncated session");
+    let (first, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(first)
+}
+
+#[test]
+fn test_sessions_persist() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("sessions");
+    let sessions = Sessions::persisted(&path)?;
+    assert!(sessions.put(b"ticket".to_vec(), b"secrets".to_vec()));
+    assert!(sessions.put(b"spare".to_vec(), b"more secrets".to_vec()));
+    assert_eq!(Some(b"secrets".to_vec()), sessions.get(b"ticket"));
+    sessions.save()?;
+
+    let loaded = Sessions::persisted(&path)?;
+    // each ticket works once
+    assert_eq!(Some(b"secrets".to_vec()), loaded.take(b"ticket"));
+    assert_eq!(None, loaded.take(b"ticket"));
+    assert_eq!(Some(b"more secrets".to_vec()), loaded.get(b"spare"));
+
+    // a day old, so gone
+    let mut buf = encode(&loaded.sessions.lock().expect("test"));
+    buf[1..9].copy_from_slice(&1u64.to_le_bytes());
+    assert!(parse(&buf)?.is_empty());
+    assert!(parse(&buf[..buf.len() - 1]).is_err());
+    Ok(())
+}
@@ -0,0 +1,199 @@
+// Bandwidth limits on the data path. Each limit is a `Shaper`: a token bucket, and a queue.
+// A stream copies at most a `QUANTUM` at a time while shaped, and, after reading it, waits
+// its turn in the queue of every shaper it's under, until the bucket is out of debt, then
+// spends what it read. The queue is first come, first served, so streams sharing a limit
+// take turns, a quantum at a time, and an interactive stream's few bytes never wait behind
+// more than a quantum from each bulk one.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::metrics::Metrics;
+
+/// the most a shaped stream moves in one turn
+pub const QUANTUM: usize = 16 * 1024;
+
+/// how a forward's, or a target's, streams are treated
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Shape {
+    /// bytes per second, in both directions together, across all its streams
+    pub bandwidth: Option<u64>,
+    /// for `quinn::SendStream::set_priority`: higher is sent first, when the connection is
+    /// congested; otherwise 0, or on the server, what the client asked for
+    pub priority: Option<i32>,
+}
+
+/// a bandwidth limit, shared by any number of streams
+pub struct Shaper {
+    name: String,
+    bucket: TokenBucket,
+    turn: tokio::sync::Mutex<()>,
+}
+
+impl Shaper {
+    /// `rate` bytes per second, in bursts of up to a second's worth; `name` is for metrics
+    pub fn new(name: impl Into<String>, rate: u64) -> Arc<Self> {
+        Arc::new(Shaper {
+            name: name.into(),
+            bucket: TokenBucket::new(rate as f64, rate as f64),
+            turn: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// one shaper per name, e.g. per target, for those named in `shapes` with a bandwidth
+    pub fn for_shapes(prefix: &str, shapes: &HashMap<String, Shape>) -> HashMap<String, Arc<Self>> {
+        shapes
+            .iter()
+            .filter_map(|(name, shape)| {
+                let shaper = Shaper::new(format!("{prefix}:{name}"), shape.bandwidth?);
+                Some((name.to_string(), shaper))
+            })
+            .collect()
+    }
+
+    /// wait our turn, then for the debt to be paid off, then spend `n`; returns how long
+    /// that took
+    pub async fn spend(&self, n: usize) -> Duration {
+        let started = Instant::now();
+        let _turn = self.turn.lock().await;
+        while let Some(debt) = self.bucket.debt() {
+            tokio::time::sleep(debt).await;
+        }
+        self.bucket.charge(n as f64);
+        started.elapsed()
+    }
+
+    pub(crate) fn is_idle(&self) -> bool {
+        self.bucket.is_full()
+    }
+}
+
+/// every limit one stream is under, and its priority
+#[derive(Clone, Default)]
+pub struct Shaping {
+    shapers: Vec<Arc<Shaper>>,
+    pub priority: i32,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Shaping {
+    /// time spent waiting is reported to `metrics`
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Shaping {
+            metrics: Some(metrics),
+            ..Shaping::default()
+        }
+    }
+
+    pub fn limit(&mut self, shaper: Option<&Arc<Shaper>>) {
+        self.shapers.extend(shaper.cloned());
+    }
+
+    /// how much to move at once, given we'd like `wanted`
+    pub fn quantum(&self, wanted: usize) -> usize {
+        if self.shapers.is_empty() {
+            wanted
+        } else {
+            wanted.min(QUANTUM)
+        }
+    }
+
+    /// account for moving `n` bytes, waiting until every limit allows it
+    pub async fn spend(&self, n: usize) {
+        for shaper in &self.shapers {
+            let waited = shaper.spend(n).await;
+            if let Some(metrics) = &self.metrics {
+                if !waited.is_zero() {
+                    metrics.throttled(&shaper.name, waited);
+                }
+            }
+        }
+    }
+}
+
+/// `rate` tokens a second, holding at most `burst`
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// starts full
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// take `n` tokens if there are that many
+    pub fn try_take(&self, n: f64) -> bool {
+        self.with_tokens(|tokens| {
+            let enough = *tokens >= n;
+            if enough {
+                *tokens -= n;
+            }
+            enough
+        })
+    }
+
+    /// take `n` tokens whether or not there are that many, going into debt
+    pub fn charge(&self, n: f64) {
+        self.with_tokens(|tokens| *tokens -= n)
+    }
+
+    /// how long until the debt is paid off, if there is any
+    pub fn debt(&self) -> Option<Duration> {
+        self.with_tokens(|tokens| {
+            (*tokens < 0.).then(|| Duration::from_secs_f64(-*tokens / self.rate))
+        })
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.with_tokens(|tokens| *tokens >= self.burst)
+    }
+
+    fn with_tokens<T>(&self, f: impl FnOnce(&mut f64) -> T) -> T {
+        let mut state = self.state.lock().expect("poisoned");
+        let (tokens, updated) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + (now - *updated).as_secs_f64() * self.rate).min(self.burst);
+        *updated = now;
+        f(tokens)
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let bucket = TokenBucket::new(1000., 100.);
+    assert!(bucket.try_take(60.));
+    assert!(!bucket.try_take(60.));
+    assert_eq!(None, bucket.debt());
+    bucket.charge(540.);
+    let debt = bucket.debt().expect("in debt");
+    assert!(debt > Duration::from_millis(400) && debt <= Duration::from_millis(500));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_shaper_takes_turns() {
+    let shaper = Shaper::new("test", 1000);
+    // the burst
+    assert_eq!(Duration::ZERO, shaper.spend(1500).await);
+    // then the debt
+    let waited = shaper.spend(10).await;
+    assert!(waited >= Duration::from_millis(500), "{waited:?}");
+
+    // a second stream queues behind the first's debt
+    let bulk = shaper.spend(1000);
+    let interactive = async {
+        tokio::task::yield_now().await;
+        shaper.spend(1).await
+    };
+    let (_, waited) = tokio::join!(bulk, interactive);
+    assert!(waited >= Duration::from_secs(1), "{waited:?}");
+}
@@ -0,0 +1,168 @@
+// The state dir, and anything else holding keys: created only accessible by us, with files
+// replaced atomically, so a crash never leaves half a key, and checked before reading, so a
+// key someone else could have read, or swapped, isn't silently trusted.
+
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::Path;
+
+#[cfg(unix)]
+use anyhow::ensure;
+use anyhow::{anyhow, Context, Result};
+#[cfg(unix)]
+use log::warn;
+
+/// create `dir`, and its parents, if necessary, only accessible by us
+pub fn ensure_private_dir(dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder
+            .create(dir)
+            .with_context(|| anyhow!("creating {dir:?}"))?;
+    }
+    check_dir(dir)
+}
+
+/// replace `path` with `data`, which only we can read
+pub fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("{path:?} has no parent directory"))?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{path:?} has no file name"))?;
+    // nobody else can have put anything in a dir which passes, so the fixed name is ours
+    check_dir(dir)?;
+    let temp = dir.join(format!(".{}.tmp", name.to_string_lossy()));
+    // left by a crash, half written
+    match fs::remove_file(&temp) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => Err(e).with_context(|| anyhow!("removing {temp:?}"))?,
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = options
+        .open(&temp)
+        .with_context(|| anyhow!("creating {temp:?}"))?;
+    file.write_all(data)
+        .and_then(|()| file.sync_all())
+        .with_context(|| anyhow!("writing {temp:?}"))?;
+    fs::rename(&temp, path).with_context(|| anyhow!("replacing {path:?}"))?;
+    sync_dir(dir)
+}
+
+/// make a rename in `dir` survive a crash, not just the renamed file's contents
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| anyhow!("syncing {dir:?}"))
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// refuse files anyone but us could have read, or written
+#[cfg(unix)]
+pub fn check_private(path: &Path) -> Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let metadata = fs::metadata(path).with_context(|| anyhow!("checking {path:?}"))?;
+    ensure!(
+        metadata.uid() == current_uid(),
+        "{path:?} is owned by uid {}, not us; refusing to use it",
+        metadata.uid()
+    );
+    let mode = metadata.permissions().mode() & 0o777;
+    ensure!(
+        mode & 0o077 == 0,
+        "{path:?} is accessible by others (mode {mode:o}); refusing to use it, try: chmod 600 {path:?}"
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_private(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let metadata = fs::metadata(dir).with_context(|| anyhow!("checking {dir:?}"))?;
+    ensure!(
+        metadata.uid() == current_uid(),
+        "{dir:?} is owned by uid {}, not us; refusing to use it",
+        metadata.uid()
+    );
+    let mode = metadata.permissions().mode() & 0o777;
+    ensure!(
+        mode & 0o022 == 0,
+        "{dir:?} is writable by others (mode {mode:o}), who could swap our files; refusing to use it, try: chmod 700 {dir:?}"
+    );
+    if mode & 0o077 != 0 {
+        warn!("{dir:?} is accessible by others (mode {mode:o}); try: chmod 700 {dir:?}");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn current_uid() -> u32 {
+    // SAFETY: geteuid can't fail, and touches no memory of ours
+    unsafe { libc::geteuid() }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_private_files() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempfile::tempdir()?;
+    let dir = root.path().join("a").join("state");
+    ensure_private_dir(&dir)?;
+    assert_eq!(0o700, fs::metadata(&dir)?.permissions().mode() & 0o777);
+    let parent = root.path().join("a");
+    assert_eq!(0o700, fs::metadata(parent)?.permissions().mode() & 0o777);
+
+    let key = dir.join("server.key");
+    write_private(&key, b"first")?;
+    write_private(&key, b"second")?;
+    assert_eq!(b"second", fs::read(&key)?.as_slice());
+    check_private(&key)?;
+
+    // a crash's leftover is replaced, not followed
+    let elsewhere = root.path().join("elsewhere");
+    std::os::unix::fs::symlink(&elsewhere, dir.join(".server.key.tmp"))?;
+    write_private(&key, b"third")?;
+    assert_eq!(b"third", fs::read(&key)?.as_slice());
+    assert!(!elsewhere.exists());
+
+    fs::set_permissions(&key, fs::Permissions::from_mode(0o644))?;
+    assert!(check_private(&key).is_err());
+
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o777))?;
+    assert!(write_private(&key, b"fourth").is_err());
+    assert!(ensure_private_dir(&dir).is_err());
+    Ok(())
+}
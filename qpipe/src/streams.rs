@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+/// the most targets totals are kept for, by name; the rest are added up under `OTHER`, so
+/// clients asking for any number of targets can't use up memory, or metrics labels
+const MAX_TARGETS: usize = 100;
+const OTHER: &str = "other";
+
+/// live streams, and how much they've moved; shared between the proxy tasks and anyone
+/// who wants to report on them
+#[derive(Default)]
+pub struct Streams {
+    next_id: AtomicU64,
+    live: Mutex<HashMap<u64, Arc<StreamInfo>>>,
+    // accepted, but not registered yet, while it's still unknown where they're going
+    accepted: AtomicUsize,
+    removed: Notify,
+    // bytes (read, written) by streams which have finished, by address_port; see `fold`
+    finished: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+pub struct StreamInfo {
+    pub id: u64,
+    // who asked for it; the local tcp peer on the client, the quic peer on the server
+    pub peer: String,
+    pub address_port: String,
+    pub started: SystemTime,
+    // bytes read from the plain (tcp) side, i.e. sent over the tunnel
+    pub read: AtomicU64,
+    // bytes written to the plain side, i.e. received over the tunnel
+    pub written: AtomicU64,
+}
+
+/// a stream that's been accepted, and counts for `drained` until it's registered, or dropped
+pub struct Accepted {
+    streams: Arc<Streams>,
+}
+
+/// keeps the stream in the registry until dropped
+pub struct StreamGuard {
+    streams: Arc<Streams>,
+    pub info: Arc<StreamInfo>,
+}
+
+impl Streams {
+    /// count a stream from as soon as it's accepted, before it's known where it's going
+    pub fn accept(self: &Arc<Self>) -> Accepted {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        Accepted {
+            streams: Arc::clone(self),
+        }
+    }
+
+    pub fn register(self: &Arc<Self>, peer: String, address_port: String) -> StreamGuard {
+        let info = Arc::new(StreamInfo {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            peer,
+            address_port,
+            started: SystemTime::now(),
+            read: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+        });
+        self.live
+            .lock()
+            .expect("poisoned")
+            .insert(info.id, Arc::clone(&info));
+        StreamGuard {
+            streams: Arc::clone(self),
+            info,
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<Arc<StreamInfo>> {
+        let mut streams = self
+            .live
+            .lock()
+            .expect("poisoned")
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        streams.sort_by_key(|s| s.id);
+        streams
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.lock().expect("poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// how many have ever been registered
+    pub fn started(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    /// bytes (read, written) by every stream, live or finished, by address_port, for the
+    /// busiest `MAX_TARGETS`, then the rest as `OTHER`
+    pub fn totals(&self) -> Vec<(String, u64, u64)> {
+        // held over the snapshot, so a stream finishing can't be counted twice, or not at all
+        let finished = self.finished.lock().expect("poisoned");
+        let mut totals = finished.clone();
+        for stream in self.snapshot() {
+            let total = totals.entry(stream.address_port.to_string()).or_default();
+            total.0 += stream.read.load(Ordering::Relaxed);
+            total.1 += stream.written.load(Ordering::Relaxed);
+        }
+        drop(finished);
+        fold(&mut totals);
+        let mut totals = totals
+            .into_iter()
+            .map(|(address_port, (read, written))| (address_port, read, written))
+            .collect::<Vec<_>>();
+        totals.sort();
+        totals
+    }
+
+    /// wait until there are no live streams, nor any accepted
+    pub async fn drained(&self) {
+        loop {
+            let removed = self.removed.notified();
+            if self.is_empty() && self.accepted.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            removed.await;
+        }
+    }
+}
+
+impl Accepted {
+    pub fn register(self, peer: String, address_port: String) -> StreamGuard {
+        // registered before this is dropped, so it's never counted as neither
+        self.streams.register(peer, address_port)
+    }
+}
+
+impl Drop for Accepted {
+    fn drop(&mut self) {
+        self.streams.accepted.fetch_sub(1, Ordering::Relaxed);
+        self.streams.removed.notify_waiters();
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut finished = self.streams.finished.lock().expect("poisoned");
+        self.streams
+            .live
+            .lock()
+            .expect("poisoned")
+            .remove(&self.info.id);
+        let total = finished
+            .entry(self.info.address_port.to_string())
+            .or_default();
+        total.0 += self.info.read.load(Ordering::Relaxed);
+        total.1 += self.info.written.load(Ordering::Relaxed);
+        fold(&mut finished);
+        drop(finished);
+        self.streams.removed.notify_waiters();
+    }
+}
+
+/// add up all but the `MAX_TARGETS` busiest targets under `OTHER`
+fn fold(totals: &mut HashMap<String, (u64, u64)>) {
+    let named = totals.len() - usize::from(totals.contains_key(OTHER));
+    if named <= MAX_TARGETS {
+        return;
+    }
+    let mut by_size = totals
+        .iter()
+        .filter(|(target, _)| *target != OTHER)
+        .map(|(target, (read, written))| (read + written, target.to_string()))
+        .collect::<Vec<_>>();
+    by_size.sort_unstable();
+    let mut other = totals.remove(OTHER).unwrap_or_default();
+    for (_, target) in &by_size[..named - MAX_TARGETS] {
+        let (read, written) = totals.remove(target).expect("just listed");
+        other.0 += read;
+        other.1 += written;
+    }
+    totals.insert(OTHER.to_string(), other);
+}
+
+/// wraps a reader or writer, adding everything that passes through to a counter
+pub struct Counting<'c, T> {
+    inner: T,
+    count: &'c AtomicU64,
+}
+
+impl<'c, T> Counting<'c, T> {
+    pub fn new(inner: T, count: &'c AtomicU64) -> Self {
+        Counting { inner, count }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counting<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let found = buf.filled().len() - before;
+        self.count.fetch_add(found as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counting<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &res {
+            self.count.fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn test_totals_capped() {
+    let streams = Arc::new(Streams::default());
+    for i in 0..MAX_TARGETS + 10 {
+        let guard = streams.register("peer".to_string(), format!("target:{i}"));
+        guard.info.read.store(i as u64 + 1, Ordering::Relaxed);
+    }
+    let live = streams.register("peer".to_string(), "live:1".to_string());
+    live.info.written.store(1000, Ordering::Relaxed);
+
+    let totals = streams.totals();
+    assert_eq!(MAX_TARGETS + 1, totals.len());
+    // the smallest, now including the last finished, went into other
+    let other = totals.iter().find(|(target, ..)| target == OTHER);
+    assert_eq!(Some(&(OTHER.to_string(), (1..=11).sum(), 0)), other);
+    assert!(totals.iter().any(|(target, ..)| target == "live:1"));
+    let moved = totals
+        .iter()
+        .map(|(_, read, written)| read + written)
+        .sum::<u64>();
+    assert_eq!((1..=110).sum::<u64>() + 1000, moved);
+}
@@ -0,0 +1,271 @@
+// Running under systemd: sockets it bound for us (socket activation, `LISTEN_FDS`), and
+// telling it how we're doing (`sd_notify`, over the datagram socket in `NOTIFY_SOCKET`).
+// Both are simple enough protocols that there's no need for libsystemd.
+
+use std::env;
+use std::os::fd::{AsRawFd as _, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{debug, warn};
+use socket2::{Socket, Type};
+use tokio::task::JoinHandle;
+
+/// the first fd systemd passes
+const LISTEN_FDS_START: RawFd = 3;
+
+/// how often the status is refreshed, if the watchdog doesn't need it more often
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// a socket systemd bound for us, and its `FileDescriptorName=`
+pub struct Listened {
+    pub name: String,
+    pub fd: OwnedFd,
+}
+
+impl Listened {
+    /// for a `ListenDatagram=` socket
+    pub fn into_udp(self) -> Result<std::net::UdpSocket> {
+        Ok(self.expect(Type::DGRAM, "a datagram")?.into())
+    }
+
+    /// for a `ListenStream=` socket
+    pub fn into_tcp(self) -> Result<std::net::TcpListener> {
+        Ok(self.expect(Type::STREAM, "a stream")?.into())
+    }
+
+    fn expect(self, kind: Type, described: &str) -> Result<Socket> {
+        let socket = Socket::from(self.fd);
+        ensure!(
+            socket.r#type()? == kind,
+            "inherited socket {:?} isn't {described} socket",
+            self.name
+        );
+        Ok(socket)
+    }
+}
+
+/// take the sockets systemd passed us, if any; they're only handed out once. This clears
+/// the `LISTEN_*` env vars, so call it before starting any threads, e.g. a tokio runtime,
+/// which might be reading the environment meanwhile
+pub fn listen_fds() -> Result<Vec<Listened>> {
+    let fds = parse_listen_env(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    )?;
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    Ok(fds
+        .into_iter()
+        .map(|(fd, name)| {
+            // SAFETY: systemd passed us these, and the env vars are gone, so nobody else
+            // will take them
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            // our children shouldn't inherit them
+            // SAFETY: plain fcntl on an fd we own
+            if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                warn!("unable to set close-on-exec on an inherited socket");
+            }
+            Listened { name, fd }
+        })
+        .collect())
+}
+
+/// the fds, and their names, that the `LISTEN_*` vars describe, if they're for `our_pid`
+fn parse_listen_env(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    our_pid: u32,
+) -> Result<Vec<(RawFd, String)>> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().context("parsing LISTEN_PID")? != our_pid {
+        debug!("ignoring LISTEN_FDS for pid {pid}");
+        return Ok(Vec::new());
+    }
+    let count = fds.parse::<RawFd>().context("parsing LISTEN_FDS")?;
+    let names = names.map(|names| names.split(':').collect::<Vec<_>>());
+    if let Some(names) = &names {
+        ensure!(
+            names.len() == count as usize,
+            "LISTEN_FDNAMES has {} names, for {count} fds",
+            names.len()
+        );
+    }
+    Ok((0..count)
+        .map(|i| {
+            let name = names
+                .as_ref()
+                .map(|names| names[i as usize])
+                .unwrap_or("unknown");
+            (LISTEN_FDS_START + i, name.to_string())
+        })
+        .collect())
+}
+
+/// sends `sd_notify` messages, or does nothing if we weren't asked for them
+#[derive(Clone, Default)]
+pub struct Notify {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    socket: UnixDatagram,
+    path: PathBuf,
+    watchdog: Option<Duration>,
+}
+
+impl Notify {
+    /// from `NOTIFY_SOCKET`, and `WATCHDOG_USEC`, if they're set
+    pub fn from_env() -> Result<Self> {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return Ok(Notify::default());
+        };
+        let watchdog = match (env::var("WATCHDOG_USEC"), env::var("WATCHDOG_PID")) {
+            (Ok(_), Ok(pid)) if pid.parse::<u32>().ok() != Some(std::process::id()) => None,
+            (Ok(usec), _) => Some(Duration::from_micros(
+                usec.parse().context("parsing WATCHDOG_USEC")?,
+            )),
+            (Err(_), _) => None,
+        };
+        Notify::new(path, watchdog)
+    }
+
+    /// send to the socket at `path`; `@` at the start is the abstract namespace, on linux
+    pub fn new(path: impl Into<PathBuf>, watchdog: Option<Duration>) -> Result<Self> {
+        let path = path.into();
+        if path.as_os_str().to_string_lossy().starts_with('@') && !cfg!(target_os = "linux") {
+            bail!("abstract notify sockets are only supported on linux: {path:?}");
+        }
+        Ok(Notify {
+            inner: Some(Arc::new(Inner {
+                socket: UnixDatagram::unbound()?,
+                path,
+                watchdog,
+            })),
+        })
+    }
+
+    pub fn ready(&self, status: &str) {
+        self.send(&format!("READY=1\nSTATUS={status}"));
+    }
+
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={status}"));
+    }
+
+    pub fn stopping(&self, status: &str) {
+        self.send(&format!("STOPPING=1\nSTATUS={status}"));
+    }
+
+    pub fn watchdog(&self) {
+        self.send("WATCHDOG=1");
+    }
+
+    /// until aborted, keep systemd's watchdog fed, if it has one, and the status fresh
+    pub fn spawn_heartbeat(
+        &self,
+        status: impl Fn() -> String + Send + 'static,
+    ) -> Option<JoinHandle<()>> {
+        let inner = self.inner.as_ref()?;
+        // systemd suggests half the timeout
+        let interval = inner
+            .watchdog
+            .map(|watchdog| (watchdog / 2).min(STATUS_INTERVAL))
+            .unwrap_or(STATUS_INTERVAL);
+        let fed = inner.watchdog.is_some();
+        let notify = self.clone();
+        Some(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                if fed {
+                    notify.watchdog();
+                }
+                notify.status(&status());
+            }
+        }))
+    }
+
+    fn send(&self, message: &str) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        if let Err(e) = inner.send(message) {
+            warn!("notifying {:?}: {e:#}", inner.path);
+        }
+    }
+}
+
+impl Inner {
+    fn send(&self, message: &str) -> Result<()> {
+        let path = self.path.as_os_str().to_string_lossy();
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            self.socket.send_to_addr(message.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        self.socket.send_to(message.as_bytes(), &self.path)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_listen_env() -> Result<()> {
+    assert!(parse_listen_env(None, None, None, 7)?.is_empty());
+    assert!(parse_listen_env(Some("8"), Some("2"), None, 7)?.is_empty());
+    assert_eq!(
+        vec![(3, "unknown".to_string()), (4, "unknown".to_string())],
+        parse_listen_env(Some("7"), Some("2"), None, 7)?
+    );
+    assert_eq!(
+        vec![(3, "quic".to_string()), (4, "ssh".to_string())],
+        parse_listen_env(Some("7"), Some("2"), Some("quic:ssh"), 7)?
+    );
+    assert!(parse_listen_env(Some("7"), Some("2"), Some("quic"), 7).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_notify() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("notify");
+    let systemd = tokio::net::UnixDatagram::bind(&path)?;
+    let recv = || async {
+        let mut buf = [0u8; 256];
+        let len = tokio::time::timeout(Duration::from_secs(5), systemd.recv(&mut buf)).await??;
+        Ok::<_, anyhow::Error>(String::from_utf8(buf[..len].to_vec())?)
+    };
+
+    let notify = Notify::new(&path, Some(Duration::from_millis(100)))?;
+    notify.ready("serving");
+    assert_eq!("READY=1\nSTATUS=serving", recv().await?);
+
+    let heartbeat = notify
+        .spawn_heartbeat(|| "3 streams".to_string())
+        .expect("enabled");
+    assert_eq!("WATCHDOG=1", recv().await?);
+    assert_eq!("STATUS=3 streams", recv().await?);
+    heartbeat.abort();
+
+    notify.stopping("draining");
+    let mut last = recv().await?;
+    // a heartbeat may have been in flight
+    while last != "STOPPING=1\nSTATUS=draining" {
+        last = recv().await?;
+    }
+
+    // disabled does nothing, quietly
+    Notify::default().ready("nobody's listening");
+    Ok(())
+}
@@ -0,0 +1,248 @@
+// QUIC transport settings, for both ends: as `key=value` pairs on the command line, and in
+// packages (see 'tprt' in package.rs). Anything unset is left to quinn. A `preset` sets
+// several at once, for a kind of network; settings after it override its choices.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use log::warn;
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use quinn::{MtuDiscoveryConfig, TransportConfig, VarInt};
+
+use crate::wire;
+
+/// settings for the QUIC connection, defaulting to whatever quinn does
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Transport {
+    pub keep_alive: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub congestion: Option<Congestion>,
+    /// assumed before there's been a round trip to measure
+    pub initial_rtt: Option<Duration>,
+    /// the congestion window, in bytes, before there's any feedback
+    pub initial_window: Option<u64>,
+    pub mtu_discovery: Option<bool>,
+    /// bytes the peer may send, unacknowledged, across the whole connection
+    pub receive_window: Option<u64>,
+    /// bytes the peer may send, unacknowledged, on any one stream
+    pub stream_receive_window: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Congestion {
+    NewReno,
+    Cubic,
+    Bbr,
+}
+
+/// the names accepted by the `preset` setting
+pub const PRESETS: [&str; 3] = ["lan", "satellite", "lossy-mobile"];
+
+impl Transport {
+    /// for `key=value` settings from the command line, or the package
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let millis = || -> Result<Duration> {
+            Ok(Duration::from_millis(value.parse().with_context(|| {
+                anyhow!("{key} must be a number of milliseconds, not {value:?}")
+            })?))
+        };
+        let bytes = || -> Result<u64> {
+            value
+                .parse()
+                .with_context(|| anyhow!("{key} must be a number of bytes, not {value:?}"))
+        };
+        match key {
+            "keep_alive_ms" => self.keep_alive = Some(millis()?),
+            "idle_timeout_ms" => self.idle_timeout = Some(millis()?),
+            "congestion" => {
+                self.congestion = Some(match value {
+                    "newreno" => Congestion::NewReno,
+                    "cubic" => Congestion::Cubic,
+                    "bbr" => Congestion::Bbr,
+                    _ => bail!("congestion must be newreno, cubic or bbr, not {value:?}"),
+                })
+            }
+            "initial_rtt_ms" => self.initial_rtt = Some(millis()?),
+            "initial_window" => self.initial_window = Some(bytes()?),
+            "mtu_discovery" => {
+                self.mtu_discovery = Some(match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!("mtu_discovery must be on or off, not {value:?}"),
+                })
+            }
+            "receive_window" => self.receive_window = Some(bytes()?),
+            "stream_receive_window" => self.stream_receive_window = Some(bytes()?),
+            "preset" => self.preset(value)?,
+            _ => bail!("unknown transport setting {key:?}"),
+        }
+        Ok(())
+    }
+
+    fn preset(&mut self, name: &str) -> Result<()> {
+        let settings: &[(&str, &str)] = match name {
+            // short, clean paths: measure quickly, and don't hold back at the start
+            "lan" => &[
+                ("congestion", "cubic"),
+                ("initial_rtt_ms", "10"),
+                ("initial_window", "65536"),
+            ],
+            // long, fat paths: a big bandwidth-delay product needs big windows, and bbr
+            // doesn't mistake the delay for congestion
+            "satellite" => &[
+                ("congestion", "bbr"),
+                ("initial_rtt_ms", "700"),
+                ("initial_window", "131072"),
+                ("receive_window", "33554432"),
+                ("stream_receive_window", "16777216"),
+                ("keep_alive_ms", "15000"),
+            ],
+            // random loss isn't congestion, and paths change under us, so keep probing
+            // the path alive, and the mtu conservative
+            "lossy-mobile" => &[
+                ("congestion", "bbr"),
+                ("initial_rtt_ms", "200"),
+                ("mtu_discovery", "off"),
+                ("keep_alive_ms", "5000"),
+                ("idle_timeout_ms", "60000"),
+            ],
+            _ => bail!(
+                "unknown transport preset {name:?}, try: {}",
+                PRESETS.join(", ")
+            ),
+        };
+        for (key, value) in settings {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = Vec::new();
+        let millis = |duration: Duration| duration.as_millis().to_string();
+        if let Some(keep_alive) = self.keep_alive {
+            settings.push(("keep_alive_ms", millis(keep_alive)));
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            settings.push(("idle_timeout_ms", millis(idle_timeout)));
+        }
+        if let Some(congestion) = self.congestion {
+            let name = match congestion {
+                Congestion::NewReno => "newreno",
+                Congestion::Cubic => "cubic",
+                Congestion::Bbr => "bbr",
+            };
+            settings.push(("congestion", name.to_string()));
+        }
+        if let Some(initial_rtt) = self.initial_rtt {
+            settings.push(("initial_rtt_ms", millis(initial_rtt)));
+        }
+        if let Some(initial_window) = self.initial_window {
+            settings.push(("initial_window", initial_window.to_string()));
+        }
+        if let Some(mtu_discovery) = self.mtu_discovery {
+            let value = if mtu_discovery { "on" } else { "off" };
+            settings.push(("mtu_discovery", value.to_string()));
+        }
+        if let Some(receive_window) = self.receive_window {
+            settings.push(("receive_window", receive_window.to_string()));
+        }
+        if let Some(stream_receive_window) = self.stream_receive_window {
+            settings.push(("stream_receive_window", stream_receive_window.to_string()));
+        }
+        settings
+    }
+
+    /// for either end's `Endpoint`
+    pub fn config(&self) -> Result<TransportConfig> {
+        let mut config = TransportConfig::default();
+        if let Some(keep_alive) = self.keep_alive {
+            config.keep_alive_interval(Some(keep_alive));
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            config.max_idle_timeout(Some(idle_timeout.try_into()?));
+        }
+        if let Some(initial_rtt) = self.initial_rtt {
+            config.initial_rtt(initial_rtt);
+        }
+        if let Some(mtu_discovery) = self.mtu_discovery {
+            config.mtu_discovery_config(mtu_discovery.then(MtuDiscoveryConfig::default));
+        }
+        if let Some(receive_window) = self.receive_window {
+            config.receive_window(VarInt::try_from(receive_window)?);
+        }
+        if let Some(stream_receive_window) = self.stream_receive_window {
+            config.stream_receive_window(VarInt::try_from(stream_receive_window)?);
+        }
+
+        // an initial window alone is for quinn's default controller
+        match (self.congestion, self.initial_window) {
+            (None, None) => (),
+            (Some(Congestion::NewReno), window) => {
+                let mut controller = NewRenoConfig::default();
+                if let Some(window) = window {
+                    controller.initial_window(window);
+                }
+                config.congestion_controller_factory(Arc::new(controller));
+            }
+            (None | Some(Congestion::Cubic), window) => {
+                let mut controller = CubicConfig::default();
+                if let Some(window) = window {
+                    controller.initial_window(window);
+                }
+                config.congestion_controller_factory(Arc::new(controller));
+            }
+            (Some(Congestion::Bbr), window) => {
+                let mut controller = BbrConfig::default();
+                if let Some(window) = window {
+                    controller.initial_window(window);
+                }
+                config.congestion_controller_factory(Arc::new(controller));
+            }
+        }
+        Ok(config)
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let strings = self
+            .settings()
+            .into_iter()
+            .flat_map(|(key, value)| [key.to_string(), value])
+            .collect::<Vec<_>>();
+        wire::encode_strings(&strings)
+    }
+
+    pub(crate) fn parse(buf: &[u8]) -> Result<Self> {
+        let strings = wire::parse_strings(buf)?;
+        ensure!(strings.len() % 2 == 0, "transport settings must be pairs");
+        let mut transport = Transport::default();
+        for pair in strings.chunks(2) {
+            // from a newer release, perhaps; better to connect without it than not at all
+            if let Err(e) = transport.set(&pair[0], &pair[1]) {
+                warn!("ignoring package transport setting: {e:#}");
+            }
+        }
+        Ok(transport)
+    }
+}
+
+#[test]
+fn test_transport_settings() -> Result<()> {
+    let mut transport = Transport::default();
+    transport.set("preset", "satellite")?;
+    transport.set("congestion", "newreno")?;
+    assert_eq!(Some(Congestion::NewReno), transport.congestion);
+    assert_eq!(Some(Duration::from_millis(700)), transport.initial_rtt);
+
+    let read = Transport::parse(&transport.encode()?)?;
+    assert_eq!(transport, read);
+    read.config()?;
+
+    for preset in PRESETS {
+        Transport::default().set("preset", preset)?;
+    }
+    assert!(transport.set("preset", "carrier-pigeon").is_err());
+    assert!(transport.set("mtu_discovery", "maybe").is_err());
+    Ok(())
+}
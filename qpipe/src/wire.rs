@@ -1,24 +1,26 @@
-use anyhow::{bail, ensure, Context, Result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-use super::frame::HeaderHeader;
+use std::fmt;
 
-pub async fn write_data(mut writer: impl AsyncWriteExt + Unpin, buf: &[u8]) -> Result<()> {
-    HeaderHeader::data(buf.len()).write_all(&mut writer).await?;
-    writer.write_all(buf).await?;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    Ok(())
-}
+use super::extension::{is_extension, Dispatch};
+use super::frame::{HeaderHeader, Version};
 
 pub async fn write_error(
     mut writer: impl AsyncWriteExt + Unpin,
+    version: Version,
     code: u32,
-    msg: &'static str,
+    msg: &str,
 ) -> Result<()> {
-    let string_length = u8::try_from(msg.len()).expect("static messages are fixed length");
+    let mut end = msg.len().min(usize::from(u8::MAX));
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    let msg = &msg[..end];
+    let string_length = u8::try_from(msg.len()).expect("truncated above");
 
     HeaderHeader::error(string_length)
-        .write_all(&mut writer)
+        .write_all(&mut writer, version)
         .await?;
     writer.write_all(&code.to_le_bytes()).await?;
     writer.write_all(&[string_length]).await?;
@@ -27,33 +29,97 @@ pub async fn write_error(
     Ok(())
 }
 
+pub fn parse_error(buf: &[u8]) -> Result<(u32, String)> {
+    ensure!(buf.len() > 4, "impossibly short error");
+    let code = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let message_length = usize::from(buf[4]);
+    let buf = &buf[5..];
+    ensure!(buf.len() >= message_length, "message doesn't fit in error");
+    Ok((
+        code,
+        String::from_utf8_lossy(&buf[..message_length]).to_string(),
+    ))
+}
+
+/// the most we'll buffer for a single frame, other than 'data', which is streamed
+pub const MAX_FRAME_LEN: u64 = 1024 * 1024;
+
+pub async fn read_frame(
+    mut reader: impl AsyncReadExt + Unpin,
+    version: Version,
+) -> Result<(HeaderHeader, Vec<u8>)> {
+    let hh = HeaderHeader::from(&mut reader, version).await?;
+    let buf = read_body(&mut reader, &hh).await?;
+    Ok((hh, buf))
+}
+
+/// the rest of a frame, after its header
+pub async fn read_body(
+    mut reader: impl AsyncReadExt + Unpin,
+    hh: &HeaderHeader,
+) -> Result<Vec<u8>> {
+    ensure!(hh.data_len <= MAX_FRAME_LEN, "overlong frame: {:?}", hh);
+    let mut buf = vec![0u8; usize::try_from(hh.data_len)?];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// each string prefixed with its u8 length
+pub fn encode_strings<S: AsRef<str>>(strings: &[S]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for s in strings {
+        let s = s.as_ref();
+        buf.push(u8::try_from(s.len()).with_context(|| anyhow!("{s:?} isn't under 255 bytes"))?);
+        buf.extend_from_slice(s.as_bytes());
+    }
+    Ok(buf)
+}
+
+pub fn parse_strings(mut buf: &[u8]) -> Result<Vec<String>> {
+    let mut strings = Vec::new();
+    while !buf.is_empty() {
+        let len = usize::from(buf[0]);
+        ensure!(buf.len() > len, "string doesn't fit in frame");
+        strings.push(String::from_utf8(buf[1..1 + len].to_vec())?);
+        buf = &buf[1 + len..];
+    }
+    Ok(strings)
+}
+
 #[derive(Debug, Clone)]
 pub struct Establish {
     // `t`cp, `u`dp,
     pub protocol: u8,
     // max length: 255
     pub address_port: String,
+    // FLAG_*
+    pub flags: u8,
+    // higher is sent first
+    pub priority: i32,
 }
 
 pub async fn write_establish(
     mut writer: impl AsyncWriteExt + Unpin,
+    version: Version,
     establish: &Establish,
 ) -> Result<()> {
     let addr_len = u8::try_from(establish.address_port.len())
         .context("address lengths must be under 255 bytes")?;
-    let data_len = 1 + 1 + u16::from(addr_len);
+    let data_len = 1 + 1 + u64::from(addr_len) + 1 + 4;
 
     HeaderHeader {
         four_cc: *b"con1",
         data_len,
     }
-    .write_all(&mut writer)
+    .write_all(&mut writer, version)
     .await?;
 
     // tcp/udp
     writer.write_all(&[establish.protocol]).await?;
     writer.write_all(&[addr_len]).await?;
     writer.write_all(establish.address_port.as_bytes()).await?;
+    writer.write_all(&[establish.flags]).await?;
+    writer.write_all(&establish.priority.to_le_bytes()).await?;
 
     Ok(())
 }
@@ -65,18 +131,87 @@ pub fn parse_establish(buf: &[u8]) -> Result<Establish> {
     let buf = &buf[2..];
     ensure!(buf.len() >= name_length, "name doesn't fit in request");
     let address_port = String::from_utf8(buf[..name_length].to_vec())?;
+    // older clients don't send any flags
+    let flags = buf.get(name_length).copied().unwrap_or(0);
+    // nor a priority
+    let priority = buf
+        .get(name_length + 1..name_length + 5)
+        .map(|priority| i32::from_le_bytes(priority.try_into().expect("four bytes")))
+        .unwrap_or(0);
     Ok(Establish {
         protocol,
         address_port,
+        flags,
+        priority,
     })
 }
 
-pub async fn read_okay(mut reader: impl AsyncReadExt + Unpin) -> Result<()> {
-    let resp = HeaderHeader::from(&mut reader).await?;
-    match &resp.four_cc {
-        b"okay" => (),
-        _ => bail!("unexpected response {:?}", resp),
+/// the server declined to establish a stream, with an `errm`
+#[derive(Debug)]
+pub struct Refused {
+    pub code: u32,
+    pub message: String,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refused ({}): {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// agreeing to no flags is an empty 'okay', as clients from before flags never read its
+/// body, so anything in it would be taken as the start of the next frame
+pub async fn write_okay(
+    mut writer: impl AsyncWriteExt + Unpin,
+    version: Version,
+    flags: u8,
+) -> Result<()> {
+    let body = if flags == 0 { &[][..] } else { &[flags][..] };
+    HeaderHeader {
+        four_cc: *b"okay",
+        data_len: body.len() as u64,
     }
-    // TODO: consume body
+    .write_all(&mut writer, version)
+    .await?;
+    writer.write_all(body).await?;
+    Ok(())
+}
+
+/// the flags the server agreed to, or the `Refused` error
+pub async fn read_okay(
+    mut reader: impl AsyncReadExt + Unpin,
+    version: Version,
+    dispatch: Dispatch<'_>,
+) -> Result<u8> {
+    loop {
+        let (resp, buf) = read_frame(&mut reader, version).await?;
+        match &resp.four_cc {
+            // older servers send an empty 'okay', agreeing to nothing
+            b"okay" => return Ok(buf.first().copied().unwrap_or(0)),
+            b"errm" => {
+                let (code, message) = parse_error(&buf)?;
+                return Err(Refused { code, message }.into());
+            }
+            four_cc if is_extension(four_cc) => dispatch.handle(resp.four_cc, &buf)?,
+            _ => bail!("unexpected response {:?}", resp),
+        }
+    }
+}
+
+#[test]
+fn test_establish_flags() -> Result<()> {
+    // as sent by a client from before flags
+    let old = parse_establish(b"t\x0elocalhost:2222")?;
+    assert_eq!(0, old.flags);
+    assert_eq!("localhost:2222", old.address_port);
+
+    let new = parse_establish(b"t\x0elocalhost:2222\x01")?;
+    assert_eq!(1, new.flags);
+    assert_eq!(0, new.priority);
+
+    let prioritised = parse_establish(b"t\x0elocalhost:2222\x01\xfe\xff\xff\xff")?;
+    assert_eq!(-2, prioritised.priority);
     Ok(())
 }
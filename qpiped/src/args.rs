@@ -1,9 +1,17 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
 use clap::{Args, Parser, Subcommand};
+use qpipe::client::Scheduler;
 
 #[derive(Parser)]
 pub struct Cli {
     #[clap(subcommand)]
     pub command: Command,
+    /// unlock encrypted keys with the passphrase in this file; otherwise it's taken from
+    /// $QPIPE_PASSPHRASE, or asked for
+    #[clap(long, global = true)]
+    pub passphrase_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -14,27 +22,302 @@ pub enum Command {
     Connect(Connect),
 
     Serve(Serve),
+
+    #[clap(subcommand)]
+    Fwd(Fwd),
+    Status(Status),
+
+    #[clap(subcommand)]
+    Package(Package),
+    Import(Import),
 }
 
+/// make a key which stays here, as `client.key` in the state dir, and a CSR to `issue` a
+/// package for it, as `client.csr`; `connect` then uses the key
 #[derive(Args)]
-pub struct KeyGen {}
+pub struct KeyGen {
+    #[clap(flatten)]
+    pub keys: Keys,
+}
 
+/// print a package for a new client
 #[derive(Args)]
 pub struct Issue {
-    pub csr: Vec<u8>,
+    /// a DER certificate signing request, from the `keygen` of a client which keeps its own
+    /// key; without one, a key is generated and put in the package
+    pub csr: Option<PathBuf>,
+    /// `host:port` clients should connect to, the first by default (repeatable)
+    #[clap(long)]
+    pub server: Vec<String>,
+    /// the name in the server's certificate, if not `localhost`
+    #[clap(long)]
+    pub server_name: Option<String>,
+    /// only accept this exact server certificate, not anything our CA issued
+    #[clap(long)]
+    pub pin: bool,
+    /// `source=target`, forwarded when the client doesn't ask for anything (repeatable)
+    #[clap(long, value_parser = parse_pair)]
+    pub forward: Vec<(String, String)>,
+    /// `key=value`, for clients; see `serve --help` (repeatable)
+    #[clap(long, value_parser = parse_pair)]
+    pub transport: Vec<(String, String)>,
+    /// stop clients using the package after this many days
+    #[clap(long)]
+    pub expires_in_days: Option<u64>,
+    /// encrypt the generated key in the package with a passphrase, asked for unless it's in
+    /// --package-passphrase-file or $PACKAGE_PASSPHRASE
+    #[clap(long)]
+    pub encrypt_package_key: bool,
+    #[clap(long)]
+    pub package_passphrase_file: Option<PathBuf>,
+    #[clap(flatten)]
+    pub keys: Keys,
+}
+
+#[derive(Args)]
+pub struct Keys {
+    /// encrypt the keys in the state dir, including any existing ones
+    #[clap(long)]
+    pub encrypt_keys: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Package {
+    /// show what a package contains, without any secrets
+    Inspect(PackageSource),
+    /// show the profiles saved in the state dir
+    List,
+}
+
+// where to read a package from [default: the env var PACKAGE]; not a doc comment, which
+// clap would use as the help of `connect`
+#[derive(Args)]
+pub struct PackageSource {
+    /// read the package from this file
+    #[clap(long, conflicts_with_all = ["package", "profile"])]
+    pub package_file: Option<PathBuf>,
+    /// `-`, to read the package from stdin
+    #[clap(long, conflicts_with = "profile")]
+    pub package: Option<String>,
+    /// a package saved by `import`, in the state dir or the keyring
+    #[clap(long)]
+    pub profile: Option<String>,
+}
+
+/// save a package as a profile, for `connect --profile`
+#[derive(Args)]
+pub struct Import {
+    pub name: String,
+    #[clap(flatten)]
+    pub source: PackageSource,
+    /// store it in the desktop keyring, using `secret-tool` (or $QPIPE_SECRET_TOOL),
+    /// instead of the state dir
+    #[clap(long)]
+    pub keyring: bool,
+}
+
+fn parse_pair(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected `a=b`, not {s:?}"))
 }
 
 #[derive(Args)]
 pub struct Connect {
-    pub server: String,
-    #[clap(short, long, num_args = 1, required = true)]
+    /// `host:port` [default: the package's first server]
+    pub server: Option<String>,
+    /// `host:port`, or `systemd:NAME` for a socket systemd passed us [default: the
+    /// package's forwards]
+    #[clap(short, long, num_args = 1)]
     pub source: Vec<String>,
-    #[clap(short, long, num_args = 1, required = true)]
+    #[clap(short, long, num_args = 1)]
     pub target: Vec<String>,
+    #[clap(flatten)]
+    pub package: PackageSource,
+    /// our key, for a package issued for its CSR [default: `client.key` in the state dir]
+    #[clap(long)]
+    pub key_file: Option<PathBuf>,
+    /// connect our stdin/stdout to this `host:port`, e.g. as an ssh `ProxyCommand`
+    #[clap(long, conflicts_with_all = ["source", "target", "daemon"])]
+    pub stdio: Option<String>,
+    /// keep running, accepting `fwd` and `status` commands on the control socket
+    #[clap(long)]
+    pub daemon: bool,
+    #[clap(flatten)]
+    pub control: Control,
+    #[clap(flatten)]
+    pub drain: Drain,
+    /// bytes per second, across every stream; `k`, `M` and `G` suffixes are powers of 1000
+    #[clap(long, value_parser = parse_bytes)]
+    pub bandwidth: Option<u64>,
+    /// `SOURCE=RATE`, bytes per second across the streams of the forward from `SOURCE`
+    /// (repeatable)
+    #[clap(long, value_parser = parse_bandwidth)]
+    pub forward_bandwidth: Vec<(String, u64)>,
+    /// `SOURCE=N`, priority for the streams of the forward from `SOURCE`, at both ends:
+    /// higher is sent first, e.g. ssh over bulk transfers [default: 0] (repeatable)
+    #[clap(long, value_parser = parse_priority)]
+    pub forward_priority: Vec<(String, i32)>,
+    /// `key=value`, over the package's transport settings; see `serve --help` (repeatable)
+    #[clap(long, value_parser = parse_pair)]
+    pub transport: Vec<(String, String)>,
+    /// parallel connections to the server, each with its own congestion control and local
+    /// port; a lost one is reconnected, unless it's the only one
+    #[clap(long, default_value_t = 1)]
+    pub connections: usize,
+    /// which connection new streams go over: round-robin, least-streams, or target-hash
+    #[clap(long, default_value = "round-robin")]
+    pub scheduler: Scheduler,
+    /// stay on the same local sockets when the network changes, rather than moving the
+    /// connections to fresh ones (changes are only noticed on linux)
+    #[clap(long)]
+    pub no_migrate: bool,
+    /// don't connect until a forward accepts its first stream, and hang up again once
+    /// there have been none for a while; see --idle-close
+    #[clap(long, conflicts_with = "stdio")]
+    pub on_demand: bool,
+    /// with --on-demand, seconds without any streams before hanging up
+    #[clap(long, default_value_t = 300, requires = "on_demand")]
+    pub idle_close: u64,
+    /// serve prometheus metrics on `http://<address>/metrics`
+    #[clap(long)]
+    pub metrics: Option<SocketAddr>,
+}
+
+#[derive(Args)]
+pub struct Drain {
+    /// on SIGTERM/SIGINT, seconds to wait for established streams to finish
+    #[clap(long, default_value_t = 30)]
+    pub drain_timeout: u64,
+}
+
+#[derive(Args)]
+pub struct Control {
+    /// the daemon's control socket [default: in the runtime directory]
+    #[clap(long)]
+    pub control: Option<PathBuf>,
+}
+
+/// manage the forwards of a running daemon
+#[derive(Subcommand)]
+pub enum Fwd {
+    Add(FwdAdd),
+    Rm(FwdRm),
+    List(Control),
+}
+
+#[derive(Args)]
+pub struct FwdAdd {
+    pub source: String,
+    pub target: String,
+    #[clap(flatten)]
+    pub control: Control,
+}
+
+#[derive(Args)]
+pub struct FwdRm {
+    pub source: String,
+    #[clap(flatten)]
+    pub control: Control,
+}
+
+/// show the connection and live streams of a running daemon
+#[derive(Args)]
+pub struct Status {
+    #[clap(flatten)]
+    pub control: Control,
 }
 
 #[derive(Args)]
 pub struct Serve {
-    #[clap(default_value = "[::]:60010")]
-    pub bind_address: String,
+    /// `host:port`s to listen on, every address each resolves to [default: 0.0.0.0:60010
+    /// and [::]:60010, or just [::]:60010 with --dual-stack, unless systemd passed us
+    /// sockets]
+    pub bind_address: Vec<String>,
+    /// let IPv6 addresses accept IPv4 too, so `[::]:port` alone is enough; no IPv4 address
+    /// can be given for the same port then
+    #[clap(long)]
+    pub dual_stack: bool,
+    /// names for the server's certificate, when it's first generated (repeatable)
+    #[clap(long, default_value = "localhost")]
+    pub server_name: Vec<String>,
+    #[clap(flatten)]
+    pub keys: Keys,
+    #[clap(flatten)]
+    pub drain: Drain,
+    #[clap(flatten)]
+    pub limits: Limits,
+    /// `key=value`, applied in order: `preset` (lan, satellite or lossy-mobile),
+    /// `congestion` (newreno, cubic or bbr), `initial_rtt_ms`, `initial_window`,
+    /// `mtu_discovery` (on or off), `receive_window`, `stream_receive_window`,
+    /// `keep_alive_ms` or `idle_timeout_ms` (repeatable)
+    #[clap(long, value_parser = parse_pair)]
+    pub transport: Vec<(String, String)>,
+    /// bytes per second, across every stream; `k`, `M` and `G` suffixes are powers of 1000
+    #[clap(long, value_parser = parse_bytes)]
+    pub bandwidth: Option<u64>,
+    /// `TARGET=RATE`, bytes per second across streams to `TARGET`, as clients name it
+    /// (repeatable)
+    #[clap(long, value_parser = parse_bandwidth)]
+    pub target_bandwidth: Vec<(String, u64)>,
+    /// `TARGET=N`, priority for streams to `TARGET`: higher is sent first [default: what
+    /// the client asked for] (repeatable)
+    #[clap(long, value_parser = parse_priority)]
+    pub target_priority: Vec<(String, i32)>,
+    /// serve prometheus metrics on `http://<address>/metrics`
+    #[clap(long)]
+    pub metrics: Option<SocketAddr>,
+    /// append a JSON line per finished stream to this file, `-` for stdout
+    #[clap(long)]
+    pub access_log: Option<PathBuf>,
+    /// keep clients' resumable TLS sessions in the state dir, so they can resume, in 0-RTT,
+    /// after a restart; the file holds secrets, unencrypted, even with --encrypt-keys
+    #[clap(long)]
+    pub persist_sessions: bool,
+}
+
+// what each client certificate may use, across all its connections; not a doc comment,
+// which clap would use as the help of `serve`
+#[derive(Args)]
+pub struct Limits {
+    /// open connections per client certificate [default: unlimited]
+    #[clap(long)]
+    pub max_connections: Option<usize>,
+    /// open streams per client certificate, across its connections [default: unlimited]
+    #[clap(long)]
+    pub max_streams: Option<usize>,
+    /// new streams per second per client certificate [default: unlimited]
+    #[clap(long)]
+    pub max_stream_rate: Option<f64>,
+    /// bytes per second per client certificate, both directions together; `k`, `M` and `G`
+    /// suffixes are powers of 1000 [default: unlimited]
+    #[clap(long, value_parser = parse_bytes)]
+    pub max_bandwidth: Option<u64>,
+}
+
+fn parse_bandwidth(s: &str) -> Result<(String, u64), String> {
+    let (name, rate) = parse_pair(s)?;
+    Ok((name, parse_bytes(&rate)?))
+}
+
+fn parse_priority(s: &str) -> Result<(String, i32), String> {
+    let (name, priority) = parse_pair(s)?;
+    let priority = priority
+        .parse()
+        .map_err(|_| format!("expected a whole number priority, not {priority:?}"))?;
+    Ok((name, priority))
+}
+
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'k')) => (&s[..i], 1_000),
+        Some((i, 'M')) => (&s[..i], 1_000_000),
+        Some((i, 'G')) => (&s[..i], 1_000_000_000),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("expected a number of bytes, like `500k`, not {s:?}"))
 }
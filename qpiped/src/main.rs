@@ -1,22 +1,35 @@
 mod args;
 
-use std::env;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future;
+use std::io::Read as _;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
-
-use anyhow::{anyhow, bail, Context, Result};
-// why
-use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
-use qpipe::certs::generate_client_certs;
-use qpipe::frame::{FourCc, HeaderHeader};
-use qpipe::package::read_package;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, io};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use log::{info, warn};
+use qpipe::certs::{generate_client_certs, KeyStorage};
+#[cfg(unix)]
+use qpipe::control::Request;
+use qpipe::package::{read_package, ClientKey, Package};
+use qpipe::profiles::{Keyring, Profiles};
+use qpipe::seal::Unlock;
 use qpipe::server::Certs;
-use tokio::io::AsyncWriteExt;
+use qpipe::shaping::Shape;
+use qpipe::transport::Transport;
+use tokio::select;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::args::{Command, Connect, Issue, KeyGen, Serve};
+use crate::args::{
+    Command, Connect, Control, Fwd, FwdAdd, FwdRm, Import, Issue, KeyGen, Keys, PackageSource,
+    Serve, Status,
+};
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     env_logger::init();
 
     use clap::Parser as _;
@@ -26,60 +39,207 @@ async fn main() -> Result<()> {
         .ok_or(anyhow!("unable to locate ('XDG') state directory"))?;
     let shared = Shared {
         state_dir: dirs.data_local_dir().to_path_buf(),
+        control_socket: dirs
+            .runtime_dir()
+            .unwrap_or_else(|| dirs.data_local_dir())
+            .join("qpiped.sock"),
+        passphrase_file: args.passphrase_file,
+        #[cfg(unix)]
+        listened: Cell::new(qpipe::systemd::listen_fds()?),
     };
+    tokio::runtime::Runtime::new()?.block_on(run(&shared, args.command))
+}
 
-    match args.command {
-        Command::KeyGen(sub) => keygen(&shared, sub).await,
-        Command::Issue(sub) => issue(&shared, sub).await,
-        Command::Connect(sub) => connect(&shared, sub).await,
-        Command::Serve(sub) => serve(&shared, sub).await,
-    }?;
-
-    Ok(())
+async fn run(shared: &Shared, command: Command) -> Result<()> {
+    match command {
+        Command::KeyGen(sub) => keygen(shared, sub).await,
+        Command::Issue(sub) => issue(shared, sub).await,
+        Command::Connect(sub) => connect(shared, sub).await,
+        Command::Serve(sub) => serve(shared, sub).await,
+        Command::Fwd(sub) => fwd(shared, sub).await,
+        Command::Status(sub) => status(shared, sub).await,
+        Command::Package(sub) => package(shared, sub).await,
+        Command::Import(sub) => import(shared, sub).await,
+    }
 }
 
 struct Shared {
     state_dir: PathBuf,
+    control_socket: PathBuf,
+    passphrase_file: Option<PathBuf>,
+    /// sockets systemd passed us, taken before the runtime started
+    #[cfg(unix)]
+    listened: Cell<Vec<qpipe::systemd::Listened>>,
+}
+
+impl Shared {
+    fn control_socket(&self, control: Control) -> PathBuf {
+        control
+            .control
+            .unwrap_or_else(|| self.control_socket.to_path_buf())
+    }
+
+    /// where a daemon listens: as above, but creating our default's directory if need be
+    fn listen_control_socket(&self, control: Control) -> Result<PathBuf> {
+        if let Some(path) = control.control {
+            return Ok(path);
+        }
+        if let Some(dir) = self.control_socket.parent() {
+            qpipe::state::ensure_private_dir(dir)?;
+        }
+        Ok(self.control_socket.clone())
+    }
+
+    /// for our own encrypted keys: from --passphrase-file, $QPIPE_PASSPHRASE, or asked for
+    fn unlock(&self) -> Unlock {
+        let file = self.passphrase_file.clone();
+        Unlock::new(move |what| {
+            if let Some(file) = &file {
+                return read_passphrase_file(file);
+            }
+            if let Ok(passphrase) = env::var("QPIPE_PASSPHRASE") {
+                return Ok(passphrase);
+            }
+            Ok(rpassword::prompt_password(format!(
+                "passphrase for {what}: "
+            ))?)
+        })
+    }
+
+    fn key_storage(&self, keys: &Keys) -> KeyStorage {
+        KeyStorage {
+            unlock: self.unlock(),
+            encrypt: keys.encrypt_keys,
+        }
+    }
+}
+
+fn read_passphrase_file(path: &Path) -> Result<String> {
+    let passphrase =
+        fs::read_to_string(path).with_context(|| anyhow!("reading passphrase from {path:?}"))?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
 }
 
-async fn keygen(_shared: &Shared, _args: KeyGen) -> Result<()> {
-    let (csr, keys) = generate_client_certs()?;
+async fn keygen(shared: &Shared, args: KeyGen) -> Result<()> {
+    qpipe::certs::client_request(&shared.state_dir, &shared.key_storage(&args.keys))?;
+    println!("{}", shared.state_dir.join("client.csr").display());
     Ok(())
 }
 
 async fn issue(shared: &Shared, args: Issue) -> Result<()> {
-    let (ca_cert, ca_key) = qpipe::certs::server(&shared.state_dir, &["localhost"])?;
-    let client_cert = qpipe::certs::mint_client(&ca_key, &qpipe::certs::parse_client(&args.csr)?)?;
+    let storage = shared.key_storage(&args.keys);
+    let (ca_cert, ca_key) = qpipe::certs::ca(&shared.state_dir, &storage)?;
+    let (csr, client_key) = match &args.csr {
+        Some(path) => (
+            fs::read(path).with_context(|| anyhow!("reading CSR from {path:?}"))?,
+            None,
+        ),
+        None => {
+            let (csr, key) = generate_client_certs()?;
+            let key = match package_passphrase(&args)? {
+                Some(passphrase) => ClientKey::Sealed(qpipe::seal::seal(&key.0, &passphrase)?),
+                None => ClientKey::Plain(key),
+            };
+            (csr.0, Some(key))
+        }
+    };
+    let client_cert =
+        qpipe::certs::mint_client(&ca_cert, &ca_key, &qpipe::certs::parse_client(&csr)?)?;
     drop(ca_key);
 
-    let mut buf = Vec::new();
-    write_der(&mut buf, *b"scrt", &ca_cert.0).await?;
-    write_der(&mut buf, *b"ccrt", &client_cert.0).await?;
-    HeaderHeader::finished().write_all(&mut buf).await?;
-    println!("qpipe1:{}", base64.encode(buf));
+    let mut package = Package::new(ca_cert, client_cert);
+    package.client_key = client_key;
+    package.servers = args.server;
+    package.server_name = args.server_name;
+    if args.pin {
+        let (chain, _key) =
+            qpipe::certs::server(&shared.state_dir, &[package.server_name()], &storage)?;
+        package.server_pin = Some(qpipe::certs::pin(&chain[0]));
+    }
+    package.forwards = args.forward;
+    for (key, value) in &args.transport {
+        package.transport.set(key, value)?;
+    }
+    package.expires = args
+        .expires_in_days
+        .map(|days| SystemTime::now() + Duration::from_secs(days * 24 * 60 * 60));
+
+    println!("{}", package.encode().await?);
     Ok(())
 }
 
-async fn write_der(
-    mut writer: impl AsyncWriteExt + Unpin,
-    four_cc: FourCc,
-    der: &[u8],
-) -> Result<()> {
-    HeaderHeader {
-        four_cc,
-        data_len: u16::try_from(der.len())?,
+/// for encrypting the key in a package we're issuing; not our own passphrase
+fn package_passphrase(args: &Issue) -> Result<Option<String>> {
+    if let Some(path) = &args.package_passphrase_file {
+        return read_passphrase_file(path).map(Some);
     }
-    .write_all(&mut writer)
-    .await?;
-    writer.write_all(der).await?;
-    Ok(())
+    if let Ok(passphrase) = env::var("PACKAGE_PASSPHRASE") {
+        return Ok(Some(passphrase));
+    }
+    if !args.encrypt_package_key {
+        return Ok(None);
+    }
+    let passphrase = rpassword::prompt_password("passphrase for the package's key: ")?;
+    ensure!(
+        passphrase == rpassword::prompt_password("and again: ")?,
+        "passphrases didn't match"
+    );
+    Ok(Some(passphrase))
 }
 
-async fn connect(_shared: &Shared, args: Connect) -> Result<()> {
-    let package = env::var("PACKAGE").context("env var PACKAGE must contain a package")?;
-    let certs = read_package(&package).await?;
+async fn connect(shared: &Shared, args: Connect) -> Result<()> {
+    ensure!(
+        args.stdio.is_none() || args.package.package.is_none(),
+        "--stdio needs stdin for the stream, so can't also read the package from it"
+    );
+    let package = read_package(&load_package(shared, &args.package)?).await?;
+    package.ensure_current()?;
+    let certs = match (&package.client_key, args.key_file) {
+        (None, key_file) => {
+            let path = key_file.unwrap_or_else(|| shared.state_dir.join("client.key"));
+            package.client_certs_with(qpipe::certs::client_key(&path, &shared.unlock())?)
+        }
+        (Some(_), None) => package.client_certs(&shared.unlock())?,
+        (Some(_), Some(_)) => bail!("the package has its own key, so doesn't need --key-file"),
+    };
+    let server = match args.server {
+        Some(server) => server,
+        None => package
+            .servers
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow!("no server given, and the package doesn't name one"))?,
+    };
+    let mut transport = package.transport.clone();
+    for (key, value) in &args.transport {
+        transport.set(key, value)?;
+    }
+    let mut options = qpipe::client::Options {
+        control: match args.daemon {
+            true => Some(shared.listen_control_socket(args.control)?),
+            false => None,
+        },
+        drain_timeout: Duration::from_secs(args.drain.drain_timeout),
+        metrics: args.metrics,
+        server_name: package.server_name().to_string(),
+        server_pin: package.server_pin.clone(),
+        transport,
+        bandwidth: args.bandwidth,
+        shapes: shapes(args.forward_bandwidth, args.forward_priority),
+        connections: args.connections,
+        scheduler: args.scheduler,
+        migrate: !args.no_migrate,
+        on_demand: args.on_demand.then(|| Duration::from_secs(args.idle_close)),
+        ..Default::default()
+    };
+    if let Some(address_port) = args.stdio {
+        return qpipe::client::stdio(server, &certs, address_port, &options).await;
+    }
     let mut mappings = Vec::new();
     match (args.source.len(), args.target.len()) {
+        (0, 0) if !package.forwards.is_empty() => mappings.extend(package.forwards),
+        (0, 0) if args.daemon => (),
+        (0, 0) => bail!("nothing to forward: give a --source and --target, or --daemon"),
         (1, 1) => mappings.push((args.source[0].to_string(), args.target[0].to_string())),
         // (_, 1) => all sources mapped onto that target
         // (1, _) => that sourc mapped onto all targets
@@ -90,25 +250,259 @@ async fn connect(_shared: &Shared, args: Connect) -> Result<()> {
             args.target
         ),
     };
-    qpipe::client::run(args.server, &certs, &mappings).await?;
+    #[cfg(unix)]
+    {
+        options.listeners = inherited_sources(shared.listened.take(), &mut mappings)?;
+        options.notify = qpipe::systemd::Notify::from_env()?;
+    }
+    qpipe::client::run(server, &certs, &mappings, &options, shutdown_signal()).await?;
     Ok(())
 }
 
-async fn serve(_shared: &Shared, args: Serve) -> Result<()> {
-    let dirs = directories::ProjectDirs::from("xxx", "fau", "qpiped").unwrap();
-    let path = dirs.data_local_dir();
-    let (cert, key) = qpipe::certs::server(path, &["localhost"])?;
-    let addrs = args.bind_address.to_socket_addrs()?.collect::<Vec<_>>();
-    if 1 != addrs.len() {
-        bail!("wrong number of interfaces for me! {:?}", addrs);
+/// the per-forward, or per-target, flags, by name
+fn shapes(bandwidth: Vec<(String, u64)>, priority: Vec<(String, i32)>) -> HashMap<String, Shape> {
+    let mut shapes = HashMap::<String, Shape>::new();
+    for (name, rate) in bandwidth {
+        shapes.entry(name).or_default().bandwidth = Some(rate);
+    }
+    for (name, priority) in priority {
+        shapes.entry(name).or_default().priority = Some(priority);
+    }
+    shapes
+}
+
+/// take out `systemd:NAME` sources, for the listener systemd passed us with that name
+#[cfg(unix)]
+fn inherited_sources(
+    mut inherited: Vec<qpipe::systemd::Listened>,
+    mappings: &mut Vec<(String, String)>,
+) -> Result<Vec<(String, std::net::TcpListener, String)>> {
+    let mut listeners = Vec::new();
+    for (source, target) in std::mem::take(mappings) {
+        let Some(name) = source.strip_prefix("systemd:") else {
+            mappings.push((source, target));
+            continue;
+        };
+        let index = inherited
+            .iter()
+            .position(|listened| listened.name == name)
+            .ok_or_else(|| anyhow!("systemd didn't pass us a socket named {name:?}"))?;
+        let listener = inherited.remove(index).into_tcp()?;
+        listeners.push((source, listener, target));
+    }
+    for unused in inherited {
+        warn!("ignoring socket {:?} from systemd", unused.name);
+    }
+    Ok(listeners)
+}
+
+async fn package(shared: &Shared, args: args::Package) -> Result<()> {
+    match args {
+        args::Package::Inspect(source) => {
+            for line in read_package(&load_package(shared, &source)?)
+                .await?
+                .describe()
+            {
+                println!("{line}");
+            }
+        }
+        args::Package::List => {
+            for name in Profiles::new(&shared.state_dir).list()? {
+                println!("{name}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn import(shared: &Shared, args: Import) -> Result<()> {
+    let package = load_package(shared, &args.source)?;
+    // refuse to save something unusable
+    read_package(&package).await?;
+    if args.keyring {
+        keyring().save(&args.name, package.trim())
+    } else {
+        Profiles::new(&shared.state_dir).save(&args.name, package.trim())
+    }
+}
+
+/// the package text, from wherever we were told; the env var PACKAGE by default
+fn load_package(shared: &Shared, source: &PackageSource) -> Result<String> {
+    if let Some(path) = &source.package_file {
+        return fs::read_to_string(path).with_context(|| anyhow!("reading package from {path:?}"));
+    }
+    if let Some(package) = &source.package {
+        // not the package itself, which would leak through `ps` and shell history
+        ensure!(
+            package == "-",
+            "--package only accepts `-`, for stdin; use --package-file for a file"
+        );
+        let mut package = String::new();
+        io::stdin()
+            .read_to_string(&mut package)
+            .context("reading package from stdin")?;
+        return Ok(package);
+    }
+    if let Some(name) = &source.profile {
+        if let Some(package) = Profiles::new(&shared.state_dir).load(name)? {
+            return Ok(package);
+        }
+        return keyring()
+            .load(name)
+            .with_context(|| anyhow!("no profile {name:?} in the state dir, trying the keyring"))?
+            .ok_or_else(|| anyhow!("no profile named {name:?}, in the state dir or the keyring"));
+    }
+    env::var("PACKAGE").context(
+        "a package is needed: --package-file, --package - (stdin), --profile, or the env var PACKAGE",
+    )
+}
+
+fn keyring() -> Keyring {
+    env::var_os("QPIPE_SECRET_TOOL")
+        .map(Keyring::new)
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+async fn fwd(shared: &Shared, args: Fwd) -> Result<()> {
+    let (control, request) = match args {
+        Fwd::Add(FwdAdd {
+            source,
+            target,
+            control,
+        }) => (control, Request::AddForward { source, target }),
+        Fwd::Rm(FwdRm { source, control }) => (control, Request::RemoveForward { source }),
+        Fwd::List(control) => (control, Request::ListForwards),
+    };
+    control_request(&shared.control_socket(control), &request).await
+}
+
+#[cfg(unix)]
+async fn status(shared: &Shared, args: Status) -> Result<()> {
+    control_request(&shared.control_socket(args.control), &Request::Status).await
+}
+
+#[cfg(unix)]
+async fn control_request(path: &Path, request: &Request) -> Result<()> {
+    for line in qpipe::control::request(path, request).await? {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn fwd(_shared: &Shared, _args: Fwd) -> Result<()> {
+    bail!("control sockets are not supported on this platform")
+}
+
+#[cfg(not(unix))]
+async fn status(_shared: &Shared, _args: Status) -> Result<()> {
+    bail!("control sockets are not supported on this platform")
+}
+
+async fn serve(shared: &Shared, args: Serve) -> Result<()> {
+    let names = args
+        .server_name
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let (chain, key) =
+        qpipe::certs::server(&shared.state_dir, &names, &shared.key_storage(&args.keys))?;
+    #[cfg(unix)]
+    let sockets = shared
+        .listened
+        .take()
+        .into_iter()
+        .map(|listened| listened.into_udp())
+        .collect::<Result<Vec<_>>>()?;
+    #[cfg(not(unix))]
+    let sockets = Vec::new();
+
+    let mut bind_addresses = args.bind_address;
+    if bind_addresses.is_empty() && sockets.is_empty() {
+        bind_addresses = vec!["[::]:60010".to_string()];
+        // otherwise that's IPv6 only
+        if !args.dual_stack {
+            bind_addresses.insert(0, "0.0.0.0:60010".to_string());
+        }
+    }
+    let mut transport = Transport::default();
+    for (key, value) in &args.transport {
+        transport.set(key, value)?;
+    }
+    let mut addrs = Vec::new();
+    for bind_address in &bind_addresses {
+        for addr in bind_address
+            .to_socket_addrs()
+            .with_context(|| anyhow!("resolving {bind_address:?}"))?
+        {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
     }
     qpipe::server::run(
         Certs {
             server_key: key,
-            server_chain: vec![cert],
+            server_chain: chain,
+        },
+        addrs,
+        qpipe::server::Options {
+            drain_timeout: Duration::from_secs(args.drain.drain_timeout),
+            metrics: args.metrics,
+            access_log: args.access_log,
+            dual_stack: args.dual_stack,
+            limits: qpipe::limits::Limits {
+                connections: args.limits.max_connections,
+                streams: args.limits.max_streams,
+                stream_rate: args.limits.max_stream_rate,
+                bandwidth: args.limits.max_bandwidth,
+            },
+            bandwidth: args.bandwidth,
+            shapes: shapes(args.target_bandwidth, args.target_priority),
+            transport,
+            sockets,
+            sessions: args
+                .persist_sessions
+                .then(|| shared.state_dir.join("sessions")),
+            #[cfg(unix)]
+            notify: qpipe::systemd::Notify::from_env()?,
+            ..Default::default()
         },
-        addrs[0],
+        shutdown_signal(),
     )
     .await?;
     Ok(())
 }
+
+/// resolves on the first SIGINT (ctrl+c) or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("unable to listen for SIGTERM: {:?}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    // failing to listen for one mustn't stop us listening for the other
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("unable to listen for ctrl+c: {:?}", e);
+            future::pending::<()>().await;
+        }
+    };
+
+    select! {
+        () = interrupt => (),
+        () = terminate => (),
+    }
+    info!("shutdown requested");
+}